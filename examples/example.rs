@@ -1,3 +1,6 @@
+// The example runs all replicas on one thread.
+#![allow(clippy::arc_with_non_send_sync)]
+
 use std::sync::Arc;
 use vsr_rs::{Client, Config, Replica, StateMachine};
 
@@ -30,6 +33,7 @@ fn main() {
         client_tx,
         replica_tx.clone(),
     );
+    #[allow(clippy::useless_vec)]
     let replicas = vec![replica_a, replica_b, replica_c];
     let tick = || {
         while !replica_rx.is_empty() {
@@ -49,6 +53,8 @@ fn main() {
     tick();
 }
 
+// The operands are only printed, which dead code analysis ignores.
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 enum Op {
     Add(i32),
@@ -60,6 +66,7 @@ struct Accumulator {}
 impl StateMachine for Accumulator {
     type Input = Op;
     type Output = ();
+    type Query = ();
 
     fn apply(&self, op: Op) {
         println!("Applying {:?}", op);
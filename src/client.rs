@@ -1,5 +1,5 @@
 use crate::config::Config;
-use crate::message::Message;
+use crate::message::{Message, ReadConsistency};
 use crate::types::{ClientID, ReplicaID, RequestNumber, ViewNumber};
 use crossbeam_channel::Sender;
 use log::trace;
@@ -11,23 +11,28 @@ use std::sync::Arc;
 pub type ClientCallback = Box<dyn Fn(RequestNumber) + Send>;
 
 /// Client.
-pub struct Client<Op>
+pub struct Client<Op, Query = Op>
 where
     Op: Clone + Debug + Send,
+    Query: Clone + Debug + Send,
 {
     config: Arc<Config>,
     client_id: ClientID,
     view_number: ViewNumber,
-    replica_tx: Sender<(ReplicaID, Message<Op>)>,
+    replica_tx: Sender<(ReplicaID, Message<Op, Query>)>,
     request_number: AtomicUsize,
     callbacks: RefCell<Option<(RequestNumber, ClientCallback)>>,
 }
 
-impl<Op> Client<Op>
+impl<Op, Query> Client<Op, Query>
 where
     Op: Clone + Debug + Send,
+    Query: Clone + Debug + Send,
 {
-    pub fn new(config: Arc<Config>, replica_tx: Sender<(ReplicaID, Message<Op>)>) -> Client<Op> {
+    pub fn new(
+        config: Arc<Config>,
+        replica_tx: Sender<(ReplicaID, Message<Op, Query>)>,
+    ) -> Client<Op, Query> {
         let request_number = AtomicUsize::new(0);
         let callbacks = RefCell::new(None);
         Client {
@@ -49,6 +54,7 @@ where
             .send((
                 primary_id,
                 Message::Request {
+                    cluster_id: self.config.cluster_id,
                     client_id: self.client_id,
                     request_number,
                     op,
@@ -57,6 +63,38 @@ where
             .unwrap();
     }
 
+    /// Sends a linearizable read-only query to the primary.
+    pub fn on_query(&self, query: Query, callback: ClientCallback) {
+        let primary_id = self.config.primary_id(self.view_number);
+        self.on_query_at(primary_id, query, ReadConsistency::Linearizable, callback);
+    }
+
+    /// Sends a read-only query to replica `replica_id`, which answers it
+    /// once its state satisfies `consistency`.
+    pub fn on_query_at(
+        &self,
+        replica_id: ReplicaID,
+        query: Query,
+        consistency: ReadConsistency,
+        callback: ClientCallback,
+    ) {
+        trace!("Client {} <- query {:?}", self.client_id, query);
+        let request_number = self.request_number.fetch_add(1, Ordering::SeqCst);
+        self.callbacks.replace(Some((request_number, callback)));
+        self.replica_tx
+            .send((
+                replica_id,
+                Message::Query {
+                    cluster_id: self.config.cluster_id,
+                    client_id: self.client_id,
+                    request_number,
+                    query,
+                    consistency,
+                },
+            ))
+            .unwrap();
+    }
+
     pub fn on_message(&self) {
         let mut callbacks = self.callbacks.borrow_mut();
         if let Some((request_number, callback)) = callbacks.take() {
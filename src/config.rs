@@ -1,35 +1,630 @@
+use log::warn;
 use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
 
-use crate::types::{ReplicaID, ViewNumber};
+use crate::types::{ClusterID, ReplicaID, ViewNumber};
+
+/// Role of a replica in the cluster.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReplicaRole {
+    /// A voting member that counts towards quorums and can become primary.
+    #[default]
+    Voter,
+    /// A non-voting member that receives `Prepare` and `Commit` messages and
+    /// keeps its log and state machine up to date, but does not count
+    /// towards quorums and never becomes primary. A learner does not
+    /// acknowledge ops or heartbeats, so it makes no promises to the
+    /// primary. Promoting a learner to a voter, or demoting a voter, changes
+    /// the quorum and needs reconfiguration, which is not implemented yet.
+    Learner,
+}
+
+/// Configuration of a single replica in the cluster.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplicaConfig {
+    /// ID of the replica.
+    pub id: ReplicaID,
+    /// Network address of the replica (for example, `10.0.0.1:7000`).
+    pub address: String,
+    /// Role of the replica.
+    pub role: ReplicaRole,
+    /// Free-form metadata such as availability zone or rack.
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl ReplicaConfig {
+    pub fn new(id: ReplicaID, address: impl Into<String>) -> ReplicaConfig {
+        ReplicaConfig {
+            id,
+            address: address.into(),
+            role: ReplicaRole::Voter,
+            metadata: BTreeMap::default(),
+        }
+    }
+
+    /// Returns true if the replica counts towards quorums.
+    pub fn is_voter(&self) -> bool {
+        self.role == ReplicaRole::Voter
+    }
+}
+
+/// Default maximum number of out-of-order `Prepare` messages a backup buffers.
+pub const DEFAULT_PREPARE_BUFFER_SIZE: usize = 64;
+
+/// Default number of ticks a backup waits for a gap in its log to fill.
+/// Asking for the missing ops right away is cheap, so by default a backup
+/// does not wait for reordered `Prepare` messages.
+pub const DEFAULT_PREPARE_GAP_TIMEOUT: u64 = 0;
+
+/// Default number of ticks a backup waits for the missing ops it asked for
+/// before it falls back to state transfer.
+pub const DEFAULT_PREPARE_REPAIR_TIMEOUT: u64 = 4;
+
+/// Default number of ticks a backup waits for state transfer to complete
+/// before it asks again.
+pub const DEFAULT_STATE_TRANSFER_TIMEOUT: u64 = 4;
+
+/// Default maximum number of requests in a batch.
+pub const DEFAULT_MAX_BATCH_SIZE: usize = 1;
+
+/// Default maximum size of a batch in bytes.
+pub const DEFAULT_MAX_BATCH_BYTES: usize = 1024 * 1024;
+
+/// Default number of ticks a request waits for its batch to fill.
+pub const DEFAULT_MAX_BATCH_DELAY: u64 = 0;
+
+/// Default maximum number of ops the primary has prepared but not committed.
+pub const DEFAULT_MAX_INFLIGHT_OPS: usize = 1024;
+
+/// Default maximum number of requests waiting for room in the pipeline.
+pub const DEFAULT_MAX_QUEUED_REQUESTS: usize = 1024;
+
+/// Default safety margin for clock drift in leader leases, in ticks.
+pub const DEFAULT_LEASE_CLOCK_DRIFT: u64 = 1;
+
+/// Default maximum number of queries a replica keeps waiting.
+pub const DEFAULT_MAX_QUEUED_QUERIES: usize = 1024;
+
+/// Default number of ticks a query waits before it times out.
+pub const DEFAULT_QUERY_TIMEOUT: u64 = 10;
+
+/// Default number of ticks a backup answers `MaxLag` queries after it last
+/// heard from the primary.
+pub const DEFAULT_STALE_READ_TIMEOUT: u64 = 2;
 
 /// Configuration.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Config {
-    /// IDs of all replicas (in sorted order).
-    pub replicas: RefCell<Vec<ReplicaID>>,
+    /// ID of the cluster. Replicas drop messages that carry a different
+    /// cluster ID to guard against cross-cluster traffic.
+    pub cluster_id: ClusterID,
+    /// All replicas (in sorted order of their IDs).
+    pub replicas: RefCell<Vec<ReplicaConfig>>,
+    /// Maximum number of `Prepare` messages a backup buffers while waiting
+    /// for a gap in its log to fill. When the buffer overflows, the backup
+    /// falls back to state transfer.
+    pub prepare_buffer_size: usize,
+    /// Number of ticks a backup waits for a gap in its log to fill before it
+    /// asks the primary for the missing ops. With a timeout of zero, the
+    /// backup asks as soon as it sees the gap.
+    pub prepare_gap_timeout: u64,
+    /// Number of ticks a backup waits for the missing ops it asked for. If
+    /// the gap is still there after that, the backup falls back to state
+    /// transfer.
+    pub prepare_repair_timeout: u64,
+    /// Number of ticks a backup waits for state transfer to complete. If it
+    /// has not completed by then, the `GetState` or `NewState` message was
+    /// lost, so the backup asks again.
+    pub state_transfer_timeout: u64,
+    /// Maximum number of requests the primary collects into one `Prepare`
+    /// message.
+    pub max_batch_size: usize,
+    /// Maximum size of the ops in a batch in bytes, as reported by
+    /// `StateMachine::input_size`.
+    pub max_batch_bytes: usize,
+    /// Maximum number of ticks a request waits in an incomplete batch
+    /// before the primary replicates the batch anyway.
+    pub max_batch_delay: u64,
+    /// Maximum number of ops the primary has prepared but not yet committed.
+    /// Requests beyond this limit wait in a queue.
+    pub max_inflight_ops: usize,
+    /// Maximum number of requests waiting in the queue. The primary replies
+    /// with `ReplyError::Busy` to requests beyond this limit.
+    pub max_queued_requests: usize,
+    /// Duration of a leader lease in ticks, or `None` to disable leases.
+    ///
+    /// A backup that acknowledges a heartbeat promises not to start a view
+    /// change for the lease duration. While a quorum of such promises holds,
+    /// the primary answers queries from its local state without confirming
+    /// that it is still the primary.
+    pub lease_duration: Option<u64>,
+    /// Number of ticks the primary subtracts from the lease duration to
+    /// account for clock drift between replicas.
+    pub lease_clock_drift: u64,
+    /// Maximum number of queries waiting for a heartbeat round or for the
+    /// replica to catch up. A replica replies with `ReplyError::Busy` to
+    /// queries beyond this limit.
+    pub max_queued_queries: usize,
+    /// Number of ticks a query waits before the replica gives up on it and
+    /// replies with `ReplyError::Timeout`.
+    pub query_timeout: u64,
+    /// Number of ticks after it last heard from the primary that a backup
+    /// still answers `MaxLag` queries. After that, the backup cannot tell
+    /// how far it lags behind, so the queries wait or time out.
+    pub stale_read_timeout: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
 }
 
 impl Config {
     pub fn new() -> Config {
+        Config::with_cluster_id(0)
+    }
+
+    pub fn with_cluster_id(cluster_id: ClusterID) -> Config {
         let replicas = RefCell::new(Vec::default());
-        Config { replicas }
+        Config {
+            cluster_id,
+            replicas,
+            prepare_buffer_size: DEFAULT_PREPARE_BUFFER_SIZE,
+            prepare_gap_timeout: DEFAULT_PREPARE_GAP_TIMEOUT,
+            prepare_repair_timeout: DEFAULT_PREPARE_REPAIR_TIMEOUT,
+            state_transfer_timeout: DEFAULT_STATE_TRANSFER_TIMEOUT,
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            max_batch_bytes: DEFAULT_MAX_BATCH_BYTES,
+            max_batch_delay: DEFAULT_MAX_BATCH_DELAY,
+            max_inflight_ops: DEFAULT_MAX_INFLIGHT_OPS,
+            max_queued_requests: DEFAULT_MAX_QUEUED_REQUESTS,
+            lease_duration: None,
+            lease_clock_drift: DEFAULT_LEASE_CLOCK_DRIFT,
+            max_queued_queries: DEFAULT_MAX_QUEUED_QUERIES,
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+            stale_read_timeout: DEFAULT_STALE_READ_TIMEOUT,
+        }
     }
 
-    pub fn primary_id(&self, view_number: ViewNumber) -> ReplicaID {
+    /// Loads configuration from a file and validates it.
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+        let contents = std::fs::read_to_string(path)?;
+        let config: Config = contents.parse()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Saves configuration to a file in the format understood by `load`.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ConfigError> {
+        std::fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    /// Validates the configuration.
+    ///
+    /// A configuration must have at least one voting replica and replica
+    /// IDs must be `0..n`, because replicas are looked up by their ID.
+    /// Metadata keys must be non-empty and must not contain whitespace,
+    /// quotes or `=`, so that they can be written to a file. Timeouts for
+    /// repairing the log must be non-zero, so that a backup waits for the
+    /// replies it asked for. A batch must fit into the request queue, and
+    /// neither the batch, the queue nor the pipeline can be empty. An even
+    /// number of voting replicas is allowed, but tolerates no more failures
+    /// than one replica less, so we warn about it.
+    pub fn validate(&self) -> Result<(), ConfigError> {
         let replicas = self.replicas.borrow();
-        let idx = view_number % replicas.len();
-        replicas[idx]
+        if replicas.is_empty() {
+            return Err(ConfigError::Empty);
+        }
+        let mut ids = HashSet::new();
+        for replica in replicas.iter() {
+            if !ids.insert(replica.id) {
+                return Err(ConfigError::DuplicateReplica(replica.id));
+            }
+            if let Some(key) = replica.metadata.keys().find(|key| !is_valid_key(key)) {
+                return Err(ConfigError::InvalidMetadataKey(replica.id, key.clone()));
+            }
+        }
+        if let Some(id) = (0..replicas.len()).find(|id| !ids.contains(id)) {
+            return Err(ConfigError::MissingReplica(id));
+        }
+        if self.prepare_repair_timeout == 0 {
+            return Err(ConfigError::InvalidSetting {
+                setting: "prepare_repair_timeout",
+                reason: "must be at least 1",
+            });
+        }
+        if self.state_transfer_timeout == 0 {
+            return Err(ConfigError::InvalidSetting {
+                setting: "state_transfer_timeout",
+                reason: "must be at least 1",
+            });
+        }
+        if self.max_batch_size == 0 {
+            return Err(ConfigError::InvalidSetting {
+                setting: "max_batch_size",
+                reason: "must be at least 1",
+            });
+        }
+        if self.max_inflight_ops == 0 {
+            return Err(ConfigError::InvalidSetting {
+                setting: "max_inflight_ops",
+                reason: "must be at least 1",
+            });
+        }
+        if self.max_queued_requests == 0 {
+            return Err(ConfigError::InvalidSetting {
+                setting: "max_queued_requests",
+                reason: "must be at least 1",
+            });
+        }
+        if self.max_batch_size > self.max_queued_requests {
+            return Err(ConfigError::InvalidSetting {
+                setting: "max_batch_size",
+                reason: "must not exceed max_queued_requests",
+            });
+        }
+        let voters = replicas.iter().filter(|replica| replica.is_voter()).count();
+        if voters == 0 {
+            return Err(ConfigError::NoVoters);
+        }
+        if voters % 2 == 0 {
+            warn!(
+                "Cluster {} has an even number of voting replicas ({})",
+                self.cluster_id, voters
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the ID of the primary in view `view_number`. Only voting
+    /// replicas become primary.
+    pub fn primary_id(&self, view_number: ViewNumber) -> ReplicaID {
+        let voters = self.voter_ids();
+        let idx = view_number % voters.len();
+        voters[idx]
+    }
+
+    /// Adds a replica without a network address, which is useful for
+    /// in-process clusters.
+    pub fn add_replica(&self) -> ReplicaID {
+        self.add_replica_with_address("")
+    }
+
+    /// Adds a replica with a network address and returns its ID.
+    pub fn add_replica_with_address(&self, address: impl Into<String>) -> ReplicaID {
+        self.add_replica_with_role(address, ReplicaRole::Voter)
     }
 
-    pub fn add_replica(&self) -> usize {
+    /// Adds a non-voting learner without a network address.
+    pub fn add_learner(&self) -> ReplicaID {
+        self.add_learner_with_address("")
+    }
+
+    /// Adds a non-voting learner with a network address and returns its ID.
+    pub fn add_learner_with_address(&self, address: impl Into<String>) -> ReplicaID {
+        self.add_replica_with_role(address, ReplicaRole::Learner)
+    }
+
+    fn add_replica_with_role(&self, address: impl Into<String>, role: ReplicaRole) -> ReplicaID {
         let mut replicas = self.replicas.borrow_mut();
-        let id = replicas.len();
-        replicas.push(id);
+        let id = replicas.last().map_or(0, |replica| replica.id + 1);
+        let mut replica = ReplicaConfig::new(id, address);
+        replica.role = role;
+        replicas.push(replica);
         id
     }
 
-    pub fn quorum(&self) -> usize {
+    /// Returns the IDs of all replicas.
+    pub fn replica_ids(&self) -> Vec<ReplicaID> {
         let replicas = self.replicas.borrow();
-        replicas.len() / 2 + 1
+        replicas.iter().map(|replica| replica.id).collect()
+    }
+
+    /// Returns the IDs of the voting replicas.
+    pub fn voter_ids(&self) -> Vec<ReplicaID> {
+        let replicas = self.replicas.borrow();
+        replicas
+            .iter()
+            .filter(|replica| replica.is_voter())
+            .map(|replica| replica.id)
+            .collect()
+    }
+
+    /// Returns true if replica `replica_id` is a voting replica.
+    pub fn is_voter(&self, replica_id: ReplicaID) -> bool {
+        self.replica(replica_id)
+            .is_some_and(|replica| replica.is_voter())
+    }
+
+    /// Returns the configuration of replica `replica_id`.
+    pub fn replica(&self, replica_id: ReplicaID) -> Option<ReplicaConfig> {
+        let replicas = self.replicas.borrow();
+        replicas
+            .iter()
+            .find(|replica| replica.id == replica_id)
+            .cloned()
+    }
+
+    /// Returns the number of voting replicas that form a quorum.
+    pub fn quorum(&self) -> usize {
+        self.voter_ids().len() / 2 + 1
+    }
+}
+
+/// Placeholder for a replica without a network address in configuration files.
+const NO_ADDRESS: &str = "-";
+
+/// Marker for a non-voting learner in configuration files.
+const LEARNER: &str = "learner";
+
+/// Value of `lease_duration` when leader leases are disabled.
+const NO_LEASE: &str = "none";
+
+/// The configuration file format is line-based:
+///
+/// ```text
+/// # Comments start with a hash.
+/// cluster_id = 1
+/// max_batch_size = 16
+/// lease_duration = 10
+/// replica 0 10.0.0.1:7000 zone=a
+/// replica 1 10.0.0.2:7000 zone=b
+/// replica 2 10.0.0.3:7000 zone=c
+/// replica 3 10.0.1.1:7000 learner zone=d
+/// ```
+///
+/// Settings are named after the fields of `Config`, and settings that are
+/// missing keep their defaults. Leader leases are disabled with
+/// `lease_duration = none`.
+/// A replica without a network address has `-` in place of the address.
+/// A non-voting learner has `learner` after the address. Addresses and
+/// metadata values that are empty or contain whitespace, quotes or
+/// backslashes are written in double quotes, with `\"` and `\\` escapes.
+/// Settings and metadata keys may appear only once.
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Config, ConfigError> {
+        let mut config = Config::new();
+        let mut settings = HashSet::new();
+        let mut replicas = Vec::new();
+        for (idx, line) in s.lines().enumerate() {
+            let line_number = idx + 1;
+            let parse_error = |message: &str| ConfigError::Parse {
+                line: line_number,
+                message: message.to_string(),
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = split_fields(line).map_err(parse_error)?.into_iter();
+            if fields.next().as_deref() != Some("replica") {
+                let (key, value) = line
+                    .split_once('=')
+                    .ok_or_else(|| parse_error("expected `replica` or `key = value`"))?;
+                let key = key.trim();
+                if !settings.insert(key.to_string()) {
+                    return Err(parse_error(&format!("duplicate `{}`", key)));
+                }
+                parse_setting(&mut config, key, value.trim()).map_err(parse_error)?;
+                continue;
+            }
+            let id = fields
+                .next()
+                .ok_or_else(|| parse_error("missing replica ID"))?
+                .parse()
+                .map_err(|_| parse_error("invalid replica ID"))?;
+            let address = fields
+                .next()
+                .ok_or_else(|| parse_error("missing replica address"))?;
+            let address = if address == NO_ADDRESS {
+                String::new()
+            } else {
+                address
+            };
+            let mut replica = ReplicaConfig::new(id, address);
+            let mut fields = fields.peekable();
+            if fields.next_if(|field| field == LEARNER).is_some() {
+                replica.role = ReplicaRole::Learner;
+            }
+            for field in fields {
+                let (key, value) = field
+                    .split_once('=')
+                    .ok_or_else(|| parse_error("expected `key=value` metadata"))?;
+                if replica
+                    .metadata
+                    .insert(key.to_string(), value.to_string())
+                    .is_some()
+                {
+                    return Err(parse_error("duplicate metadata key"));
+                }
+            }
+            replicas.push(replica);
+        }
+        replicas.sort_by_key(|replica| replica.id);
+        *config.replicas.borrow_mut() = replicas;
+        Ok(config)
+    }
+}
+
+/// Sets the setting `key` of `config` to `value`.
+fn parse_setting(config: &mut Config, key: &str, value: &str) -> Result<(), &'static str> {
+    fn number<T: FromStr>(value: &str) -> Result<T, &'static str> {
+        value.parse().map_err(|_| "invalid number")
+    }
+    match key {
+        "cluster_id" => config.cluster_id = value.parse().map_err(|_| "invalid cluster ID")?,
+        "prepare_buffer_size" => config.prepare_buffer_size = number(value)?,
+        "prepare_gap_timeout" => config.prepare_gap_timeout = number(value)?,
+        "prepare_repair_timeout" => config.prepare_repair_timeout = number(value)?,
+        "state_transfer_timeout" => config.state_transfer_timeout = number(value)?,
+        "max_batch_size" => config.max_batch_size = number(value)?,
+        "max_batch_bytes" => config.max_batch_bytes = number(value)?,
+        "max_batch_delay" => config.max_batch_delay = number(value)?,
+        "max_inflight_ops" => config.max_inflight_ops = number(value)?,
+        "max_queued_requests" => config.max_queued_requests = number(value)?,
+        "lease_duration" if value == NO_LEASE => config.lease_duration = None,
+        "lease_duration" => config.lease_duration = Some(number(value)?),
+        "lease_clock_drift" => config.lease_clock_drift = number(value)?,
+        "max_queued_queries" => config.max_queued_queries = number(value)?,
+        "query_timeout" => config.query_timeout = number(value)?,
+        "stale_read_timeout" => config.stale_read_timeout = number(value)?,
+        _ => return Err("unknown setting"),
+    }
+    Ok(())
+}
+
+/// Splits a configuration line into whitespace-separated fields, removing
+/// double quotes and resolving escapes within them.
+fn split_fields(line: &str) -> Result<Vec<String>, &'static str> {
+    let mut fields = Vec::new();
+    let mut field: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let field = field.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => field.push(chars.next().ok_or("unterminated quote")?),
+                        Some(c) => field.push(c),
+                        None => return Err("unterminated quote"),
+                    }
+                }
+            }
+            c if c.is_whitespace() => fields.extend(field.take()),
+            c => field.get_or_insert_with(String::new).push(c),
+        }
+    }
+    fields.extend(field);
+    Ok(fields)
+}
+
+/// Quotes `value` if `split_fields` would not read it back unchanged.
+fn quote(value: &str) -> String {
+    let needs_quotes =
+        value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\');
+    if !needs_quotes {
+        return value.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Returns true if `key` can be used as a metadata key in a file.
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && !key.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\' || c == '=')
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "cluster_id = {}", self.cluster_id)?;
+        writeln!(f, "prepare_buffer_size = {}", self.prepare_buffer_size)?;
+        writeln!(f, "prepare_gap_timeout = {}", self.prepare_gap_timeout)?;
+        writeln!(
+            f,
+            "prepare_repair_timeout = {}",
+            self.prepare_repair_timeout
+        )?;
+        writeln!(
+            f,
+            "state_transfer_timeout = {}",
+            self.state_transfer_timeout
+        )?;
+        writeln!(f, "max_batch_size = {}", self.max_batch_size)?;
+        writeln!(f, "max_batch_bytes = {}", self.max_batch_bytes)?;
+        writeln!(f, "max_batch_delay = {}", self.max_batch_delay)?;
+        writeln!(f, "max_inflight_ops = {}", self.max_inflight_ops)?;
+        writeln!(f, "max_queued_requests = {}", self.max_queued_requests)?;
+        match self.lease_duration {
+            Some(lease_duration) => writeln!(f, "lease_duration = {}", lease_duration)?,
+            None => writeln!(f, "lease_duration = {}", NO_LEASE)?,
+        }
+        writeln!(f, "lease_clock_drift = {}", self.lease_clock_drift)?;
+        writeln!(f, "max_queued_queries = {}", self.max_queued_queries)?;
+        writeln!(f, "query_timeout = {}", self.query_timeout)?;
+        writeln!(f, "stale_read_timeout = {}", self.stale_read_timeout)?;
+        for replica in self.replicas.borrow().iter() {
+            let address = if replica.address.is_empty() {
+                NO_ADDRESS.to_string()
+            } else {
+                quote(&replica.address)
+            };
+            write!(f, "replica {} {}", replica.id, address)?;
+            if !replica.is_voter() {
+                write!(f, " {}", LEARNER)?;
+            }
+            for (key, value) in &replica.metadata {
+                write!(f, " {}={}", key, quote(value))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Configuration error.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Reading or writing the configuration file failed.
+    Io(std::io::Error),
+    /// The configuration file is malformed.
+    Parse { line: usize, message: String },
+    /// The configuration has no replicas.
+    Empty,
+    /// The same replica ID appears more than once.
+    DuplicateReplica(ReplicaID),
+    /// Replica IDs are not `0..n`; the given ID is missing.
+    MissingReplica(ReplicaID),
+    /// A metadata key of the replica cannot be written to a file.
+    InvalidMetadataKey(ReplicaID, String),
+    /// The configuration has no voting replicas.
+    NoVoters,
+    /// A setting has a value the replicas cannot work with.
+    InvalidSetting {
+        setting: &'static str,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "I/O error: {}", err),
+            ConfigError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            ConfigError::Empty => write!(f, "configuration has no replicas"),
+            ConfigError::DuplicateReplica(id) => write!(f, "duplicate replica ID {}", id),
+            ConfigError::MissingReplica(id) => write!(f, "missing replica ID {}", id),
+            ConfigError::InvalidMetadataKey(id, key) => {
+                write!(f, "invalid metadata key {:?} of replica {}", key, id)
+            }
+            ConfigError::NoVoters => write!(f, "configuration has no voting replicas"),
+            ConfigError::InvalidSetting { setting, reason } => {
+                write!(f, "invalid {}: {}", setting, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(err: std::io::Error) -> ConfigError {
+        ConfigError::Io(err)
     }
 }
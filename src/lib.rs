@@ -2,21 +2,29 @@ pub mod client;
 pub mod config;
 pub mod message;
 pub mod replica;
+#[cfg(feature = "simulator")]
+pub mod simulator;
 pub mod state_machine;
 
 mod types;
 
 pub use client::Client;
-pub use config::Config;
-pub use message::Message;
-pub use replica::Replica;
-pub use state_machine::StateMachine;
+pub use config::{Config, ConfigError, ReplicaConfig, ReplicaRole};
+pub use message::{LogEntry, Message, OpContext, ReadConsistency, Reply, ReplyError};
+pub use replica::{Clock, Replica, Status, SystemClock};
+pub use state_machine::{ApplyError, StateMachine};
 
 #[cfg(test)]
+// The tests run all replicas on one thread and index them from a `vec!`.
+#[allow(clippy::arc_with_non_send_sync, clippy::useless_vec)]
 mod tests {
-    use crate::{Client, Config, Replica, StateMachine};
+    use crate::{
+        ApplyError, Client, Clock, Config, ConfigError, LogEntry, Message, OpContext,
+        ReadConsistency, Replica, ReplicaRole, ReplyError, StateMachine, Status,
+    };
     use parking_lot::Mutex;
     use std::sync::Arc;
+    use std::time::Duration;
 
     #[test]
     fn test_normal_operation() {
@@ -181,28 +189,1231 @@ mod tests {
         assert_eq!(12, accumulator);
     }
 
+    #[test]
+    fn test_single_replica() {
+        let _ = env_logger::try_init();
+        let (client_tx, client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::new());
+        let sm = Arc::new(Accumulator::new());
+        let a_id = config.add_replica();
+        let replica = Replica::new(
+            a_id,
+            config.clone(),
+            sm.clone(),
+            client_tx,
+            replica_tx.clone(),
+        );
+        let client = Client::new(config, replica_tx);
+        client.on_request(Op::Add(10), Box::new(|_| {}));
+        let (_, message) = replica_rx.recv().unwrap();
+        replica.on_message(message);
+        assert_eq!(10, *sm.accumulator.lock());
+        assert!(client_rx.try_recv().is_ok());
+        assert!(replica_rx.is_empty());
+        client.on_request(Op::Sub(3), Box::new(|_| {}));
+        let (_, message) = replica_rx.recv().unwrap();
+        replica.on_message(message);
+        assert_eq!(7, *sm.accumulator.lock());
+        assert!(client_rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_cumulative_acks() {
+        let _ = env_logger::try_init();
+        let (client_tx, client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::new());
+        let sm = Arc::new(Accumulator::new());
+        let a_id = config.add_replica();
+        for _ in 0..4 {
+            config.add_replica();
+        }
+        let replica = Replica::new(
+            a_id,
+            config.clone(),
+            sm.clone(),
+            client_tx,
+            replica_tx.clone(),
+        );
+        let prepare_ok = |op_number, replica_id| Message::PrepareOk {
+            cluster_id: 0,
+            view_number: 0,
+            op_number,
+            replica_id,
+        };
+        let client = Client::new(config, replica_tx);
+        client.on_request(Op::Add(10), Box::new(|_| {}));
+        client.on_request(Op::Add(20), Box::new(|_| {}));
+        while let Ok((replica_id, message)) = replica_rx.try_recv() {
+            if replica_id == a_id {
+                replica.on_message(message);
+            }
+        }
+        // A duplicate acknowledgement does not count towards the quorum.
+        replica.on_message(prepare_ok(2, 1));
+        replica.on_message(prepare_ok(2, 1));
+        assert_eq!(0, *sm.accumulator.lock());
+        // The acknowledgement of the second op also covers the first op.
+        replica.on_message(prepare_ok(1, 2));
+        assert_eq!(10, *sm.accumulator.lock());
+        assert_eq!(1, client_rx.try_iter().count());
+        replica.on_message(prepare_ok(2, 3));
+        assert_eq!(30, *sm.accumulator.lock());
+        assert_eq!(1, client_rx.try_iter().count());
+        // A reordered acknowledgement does not move us backwards.
+        replica.on_message(prepare_ok(1, 3));
+        assert_eq!(30, *sm.accumulator.lock());
+    }
+
+    #[test]
+    fn test_reordered_prepare() {
+        let _ = env_logger::try_init();
+        let (client_tx, _client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let mut config = Config::new();
+        config.prepare_gap_timeout = 2;
+        let config = Arc::new(config);
+        config.add_replica();
+        let b_id = config.add_replica();
+        config.add_replica();
+        let sm = Arc::new(Accumulator::new());
+        let replica_b = Replica::new(b_id, config.clone(), sm.clone(), client_tx, replica_tx);
+        let prepare = |op_number, op, commit_number| Message::Prepare {
+            cluster_id: 0,
+            view_number: 0,
+            op_number,
+            entries: vec![LogEntry {
+                client_id: 0,
+                request_number: op_number - 1,
+                op,
+                context: OpContext::default(),
+            }],
+            commit_number,
+        };
+        // A prepare that arrives ahead of a gap is buffered.
+        replica_b.on_message(prepare(2, Op::Add(20), 1));
+        assert!(replica_rx.is_empty());
+        // After a timeout, the backup asks the primary for the missing op
+        // rather than for the whole state.
+        for _ in 0..config.prepare_gap_timeout {
+            replica_b.on_idle();
+        }
+        match replica_rx.try_recv().unwrap() {
+            (0, Message::GetPrepares { op_numbers, .. }) => assert_eq!(vec![1], op_numbers),
+            (_, message) => panic!("unexpected message {:?}", message),
+        }
+        // When the gap fills, the buffered prepare is applied too, and one
+        // `PrepareOk` message acknowledges both ops.
+        replica_b.on_message(prepare(1, Op::Add(10), 0));
+        let acked: Vec<_> = replica_rx
+            .try_iter()
+            .map(|(_, message)| match message {
+                Message::PrepareOk { op_number, .. } => op_number,
+                message => panic!("unexpected message {:?}", message),
+            })
+            .collect();
+        assert_eq!(vec![2], acked);
+        assert_eq!(10, *sm.accumulator.lock());
+    }
+
+    #[test]
+    fn test_malformed_prepare() {
+        let _ = env_logger::try_init();
+        let (client_tx, _client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::new());
+        config.add_replica();
+        let b_id = config.add_replica();
+        config.add_replica();
+        let sm = Arc::new(Accumulator::new());
+        let replica_b = Replica::new(b_id, config, sm, client_tx, replica_tx);
+        let entry = LogEntry {
+            client_id: 0,
+            request_number: 0,
+            op: Op::Add(10),
+            context: OpContext::default(),
+        };
+        let prepare = |op_number, entries| Message::Prepare {
+            cluster_id: 0,
+            view_number: 0,
+            op_number,
+            entries,
+            commit_number: 0,
+        };
+        // A prepare without entries, or with more entries than ops before
+        // it, is dropped.
+        replica_b.on_message(prepare(1, vec![]));
+        replica_b.on_message(prepare(1, vec![entry.clone(), entry.clone()]));
+        assert!(replica_rx.is_empty());
+        assert_eq!(0, replica_b.op_number());
+        replica_b.on_message(prepare(1, vec![entry]));
+        assert_eq!(1, replica_b.op_number());
+    }
+
+    #[test]
+    fn test_prepare_gap_repaired_immediately() {
+        let _ = env_logger::try_init();
+        let (client_tx, _client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::new());
+        config.add_replica();
+        let b_id = config.add_replica();
+        config.add_replica();
+        let sm = Arc::new(Accumulator::new());
+        let replica_b = Replica::new(b_id, config, sm.clone(), client_tx, replica_tx);
+        let prepare = |op_number, op, commit_number| Message::Prepare {
+            cluster_id: 0,
+            view_number: 0,
+            op_number,
+            entries: vec![LogEntry {
+                client_id: 0,
+                request_number: op_number - 1,
+                op,
+                context: OpContext::default(),
+            }],
+            commit_number,
+        };
+        // Without a gap timeout, the backup asks for the missing op as soon
+        // as it sees the gap, so it can acknowledge within the same round.
+        replica_b.on_message(prepare(3, Op::Add(30), 2));
+        match replica_rx.try_recv().unwrap() {
+            (0, Message::GetPrepares { op_numbers, .. }) => assert_eq!(vec![1, 2], op_numbers),
+            (_, message) => panic!("unexpected message {:?}", message),
+        }
+        // Another prepare ahead of the gap does not ask again, and the
+        // backup waits for the reply rather than falling back to state
+        // transfer on the next tick.
+        replica_b.on_message(prepare(4, Op::Add(40), 2));
+        replica_b.on_idle();
+        assert!(replica_rx.is_empty());
+        replica_b.on_message(prepare(1, Op::Add(10), 0));
+        replica_b.on_message(prepare(2, Op::Add(20), 1));
+        let acked: Vec<_> = replica_rx
+            .try_iter()
+            .map(|(_, message)| match message {
+                Message::PrepareOk { op_number, .. } => op_number,
+                message => panic!("unexpected message {:?}", message),
+            })
+            .collect();
+        assert_eq!(vec![1, 4], acked);
+        assert_eq!(30, *sm.accumulator.lock());
+    }
+
+    #[test]
+    fn test_request_batching() {
+        let _ = env_logger::try_init();
+        let (client_tx, client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let mut config = Config::new();
+        config.max_batch_size = 3;
+        config.max_batch_delay = 2;
+        let config = Arc::new(config);
+        let sm = Arc::new(Accumulator::new());
+        let a_id = config.add_replica();
+        let replica_a = Replica::new(
+            a_id,
+            config.clone(),
+            sm.clone(),
+            client_tx.clone(),
+            replica_tx.clone(),
+        );
+        let b_id = config.add_replica();
+        let replica_b = Replica::new(
+            b_id,
+            config.clone(),
+            Arc::new(Accumulator::new()),
+            client_tx.clone(),
+            replica_tx.clone(),
+        );
+        let c_id = config.add_replica();
+        let replica_c = Replica::new(
+            c_id,
+            config.clone(),
+            Arc::new(Accumulator::new()),
+            client_tx,
+            replica_tx.clone(),
+        );
+        let replicas = vec![replica_a, replica_b, replica_c];
+        let prepares = std::cell::Cell::new(0);
+        let tick = || {
+            while let Ok((replica_id, message)) = replica_rx.try_recv() {
+                if let Message::Prepare { .. } = message {
+                    prepares.set(prepares.get() + 1);
+                }
+                replicas[replica_id].on_message(message);
+            }
+        };
+        let client = Client::new(config, replica_tx);
+        // A full batch is replicated in one `Prepare` message per backup, but
+        // each op gets its own reply.
+        for value in [1, 2, 3] {
+            client.on_request(Op::Add(value), Box::new(|_| {}));
+        }
+        tick();
+        assert_eq!(6, *sm.accumulator.lock());
+        assert_eq!(2, prepares.get());
+        let replies: Vec<_> = client_rx
+            .try_iter()
+            .map(|reply| reply.request_number)
+            .collect();
+        assert_eq!(vec![0, 1, 2], replies);
+        // An incomplete batch is replicated after the maximum delay.
+        client.on_request(Op::Add(4), Box::new(|_| {}));
+        tick();
+        assert_eq!(6, *sm.accumulator.lock());
+        for _ in 0..2 {
+            replicas[a_id].on_idle();
+            tick();
+        }
+        assert_eq!(10, *sm.accumulator.lock());
+        assert_eq!(1, client_rx.try_iter().count());
+    }
+
+    #[test]
+    fn test_pipeline_backpressure() {
+        let _ = env_logger::try_init();
+        let (client_tx, client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let mut config = Config::new();
+        config.max_inflight_ops = 1;
+        config.max_queued_requests = 1;
+        let config = Arc::new(config);
+        let sm = Arc::new(Accumulator::new());
+        let a_id = config.add_replica();
+        config.add_replica();
+        config.add_replica();
+        let replica = Replica::new(
+            a_id,
+            config.clone(),
+            sm.clone(),
+            client_tx,
+            replica_tx.clone(),
+        );
+        let prepared = || {
+            replica_rx
+                .try_iter()
+                .filter_map(|(_, message)| match message {
+                    Message::Prepare { op_number, .. } => Some(op_number),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let request = |request_number, op| Message::Request {
+            cluster_id: 0,
+            client_id: 0,
+            request_number,
+            op,
+        };
+        replica.on_message(request(0, Op::Add(1)));
+        assert_eq!(vec![1, 1], prepared());
+        // The second request waits for the first one to commit and the third
+        // one is rejected.
+        replica.on_message(request(1, Op::Add(2)));
+        replica.on_message(request(2, Op::Add(3)));
+        assert!(prepared().is_empty());
+        let reply = client_rx.try_recv().unwrap();
+        assert_eq!(2, reply.request_number);
+        assert_eq!(Err(ReplyError::Busy), reply.result);
+        replica.on_message(Message::PrepareOk {
+            cluster_id: 0,
+            view_number: 0,
+            op_number: 1,
+            replica_id: 1,
+        });
+        assert_eq!(1, *sm.accumulator.lock());
+        assert_eq!(vec![2, 2], prepared());
+    }
+
+    #[test]
+    fn test_query() {
+        let _ = env_logger::try_init();
+        let (client_tx, client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::new());
+        let sm = Arc::new(Accumulator::new());
+        let a_id = config.add_replica();
+        let replica_a = Replica::new(
+            a_id,
+            config.clone(),
+            sm.clone(),
+            client_tx.clone(),
+            replica_tx.clone(),
+        );
+        let b_id = config.add_replica();
+        let replica_b = Replica::new(
+            b_id,
+            config.clone(),
+            Arc::new(Accumulator::new()),
+            client_tx.clone(),
+            replica_tx.clone(),
+        );
+        let c_id = config.add_replica();
+        let replica_c = Replica::new(
+            c_id,
+            config.clone(),
+            Arc::new(Accumulator::new()),
+            client_tx,
+            replica_tx.clone(),
+        );
+        let replicas = vec![replica_a, replica_b, replica_c];
+        let client = Client::new(config, replica_tx);
+        client.on_request(Op::Add(10), Box::new(|_| {}));
+        client.on_query((), Box::new(|_| {}));
+        // The primary does not answer the query before a quorum confirms it
+        // is still the primary.
+        let (replica_id, request) = replica_rx.recv().unwrap();
+        replicas[replica_id].on_message(request);
+        let (replica_id, query) = replica_rx.recv().unwrap();
+        replicas[replica_id].on_message(query);
+        assert!(client_rx.is_empty());
+        while let Ok((replica_id, message)) = replica_rx.try_recv() {
+            replicas[replica_id].on_message(message);
+        }
+        let replies: Vec<_> = client_rx.try_iter().map(|reply| reply.result).collect();
+        assert_eq!(vec![Ok(10), Ok(10)], replies);
+        // The query is not appended to the log.
+        assert_eq!(10, *sm.accumulator.lock());
+        client.on_request(Op::Add(1), Box::new(|_| {}));
+        while let Ok((replica_id, message)) = replica_rx.try_recv() {
+            replicas[replica_id].on_message(message);
+        }
+        assert_eq!(Ok(11), client_rx.try_recv().unwrap().result);
+    }
+
+    #[test]
+    fn test_lease_query() {
+        let _ = env_logger::try_init();
+        let (client_tx, client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let mut config = Config::new();
+        config.lease_duration = Some(5);
+        config.lease_clock_drift = 1;
+        let config = Arc::new(config);
+        let replicas: Vec<_> = (0..3)
+            .map(|_| {
+                Replica::new(
+                    config.add_replica(),
+                    config.clone(),
+                    Arc::new(Accumulator::new()),
+                    client_tx.clone(),
+                    replica_tx.clone(),
+                )
+            })
+            .collect();
+        let tick = || {
+            while let Ok((replica_id, message)) = replica_rx.try_recv() {
+                replicas[replica_id].on_message(message);
+            }
+        };
+        // The primary acquires a lease on its first tick.
+        replicas[0].on_idle();
+        tick();
+        assert_eq!(5, replicas[1].lease_promise());
+        let client = Client::new(config, replica_tx.clone());
+        client.on_query((), Box::new(|_| {}));
+        let (replica_id, query) = replica_rx.recv().unwrap();
+        replicas[replica_id].on_message(query);
+        // The query is answered locally without a heartbeat round.
+        assert!(replica_rx.is_empty());
+        assert_eq!(Ok(0), client_rx.try_recv().unwrap().result);
+        // The lease expires when the backups do not renew it.
+        for _ in 0..4 {
+            replicas[0].on_idle();
+            replica_rx.try_iter().for_each(drop);
+        }
+        client.on_query((), Box::new(|_| {}));
+        let (replica_id, query) = replica_rx.recv().unwrap();
+        replicas[replica_id].on_message(query);
+        assert!(client_rx.is_empty());
+        tick();
+        assert_eq!(Ok(0), client_rx.try_recv().unwrap().result);
+    }
+
+    #[test]
+    fn test_stale_query() {
+        let _ = env_logger::try_init();
+        let (client_tx, client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, _replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::new());
+        config.add_replica();
+        let b_id = config.add_replica();
+        config.add_replica();
+        let replica_b = Replica::new(
+            b_id,
+            config.clone(),
+            Arc::new(Accumulator::new()),
+            client_tx,
+            replica_tx,
+        );
+        let query = |request_number, consistency| Message::Query {
+            cluster_id: 0,
+            client_id: 0,
+            request_number,
+            query: (),
+            consistency,
+        };
+        replica_b.on_message(Message::Prepare {
+            cluster_id: 0,
+            view_number: 0,
+            op_number: 1,
+            entries: vec![LogEntry {
+                client_id: 0,
+                request_number: 0,
+                op: Op::Add(10),
+                context: OpContext::default(),
+            }],
+            commit_number: 0,
+        });
+        // The backup waits until it has committed the op the query requires.
+        replica_b.on_message(query(1, ReadConsistency::MinCommit(1)));
+        assert!(client_rx.is_empty());
+        // The backup is not lagging behind the primary it has heard from.
+        replica_b.on_message(query(2, ReadConsistency::MaxLag(0)));
+        let reply = client_rx.try_recv().unwrap();
+        assert_eq!((2, Ok(0)), (reply.request_number, reply.result));
+        replica_b.on_message(Message::Commit {
+            cluster_id: 0,
+            view_number: 0,
+            commit_number: 1,
+        });
+        let reply = client_rx.try_recv().unwrap();
+        assert_eq!((1, Ok(10)), (reply.request_number, reply.result));
+        // A backup cannot answer a linearizable query.
+        replica_b.on_message(query(3, ReadConsistency::Linearizable));
+        let reply = client_rx.try_recv().unwrap();
+        assert_eq!(
+            (3, Err(ReplyError::NotPrimary)),
+            (reply.request_number, reply.result)
+        );
+        // A backup that has not heard from the primary for a while does not
+        // know how far it lags behind, so the query waits until it times out.
+        for _ in 0..=config.stale_read_timeout {
+            replica_b.on_idle();
+        }
+        replica_b.on_message(query(4, ReadConsistency::MaxLag(0)));
+        assert!(client_rx.is_empty());
+        for _ in 0..config.query_timeout {
+            replica_b.on_idle();
+        }
+        let reply = client_rx.try_recv().unwrap();
+        assert_eq!(
+            (4, Err(ReplyError::Timeout)),
+            (reply.request_number, reply.result)
+        );
+    }
+
+    #[test]
+    fn test_query_limits() {
+        let _ = env_logger::try_init();
+        let (client_tx, client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, _replica_rx) = crossbeam_channel::unbounded();
+        let mut config = Config::new();
+        config.max_queued_queries = 1;
+        let config = Arc::new(config);
+        let a_id = config.add_replica();
+        let replica = Replica::new(
+            a_id,
+            config.clone(),
+            Arc::new(Accumulator::new()),
+            client_tx,
+            replica_tx,
+        );
+        let query = |request_number, consistency| Message::Query {
+            cluster_id: 0,
+            client_id: 0,
+            request_number,
+            query: (),
+            consistency,
+        };
+        // A query beyond the limit is refused.
+        replica.on_message(query(0, ReadConsistency::MinCommit(1)));
+        replica.on_message(query(1, ReadConsistency::MinCommit(1)));
+        let reply = client_rx.try_recv().unwrap();
+        assert_eq!(
+            (1, Err(ReplyError::Busy)),
+            (reply.request_number, reply.result)
+        );
+        // A single replica commits on its own, which completes the query.
+        replica.on_message(Message::Request {
+            cluster_id: 0,
+            client_id: 1,
+            request_number: 0,
+            op: Op::Add(10),
+        });
+        let replies: Vec<_> = client_rx
+            .try_iter()
+            .map(|reply| (reply.client_id, reply.result))
+            .collect();
+        assert_eq!(vec![(1, Ok(10)), (0, Ok(10))], replies);
+    }
+
+    #[test]
+    fn test_query_not_supported() {
+        struct Log;
+        impl StateMachine for Log {
+            type Input = Op;
+            type Output = ();
+            type Query = ();
+            fn apply(&self, _op: Op) {}
+        }
+        let _ = env_logger::try_init();
+        let (client_tx, client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, _replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::new());
+        config.add_replica();
+        let b_id = config.add_replica();
+        config.add_replica();
+        let replica_b = Replica::new(b_id, config, Arc::new(Log), client_tx, replica_tx);
+        // A state machine that does not implement `query` rejects queries.
+        replica_b.on_message(Message::Query {
+            cluster_id: 0,
+            client_id: 0,
+            request_number: 0,
+            query: (),
+            consistency: ReadConsistency::MinCommit(0),
+        });
+        assert!(matches!(
+            client_rx.try_recv().unwrap().result,
+            Err(ReplyError::Rejected(_))
+        ));
+    }
+
+    #[test]
+    fn test_learner() {
+        let _ = env_logger::try_init();
+        let (client_tx, client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::new());
+        let a_id = config.add_replica();
+        let b_id = config.add_replica();
+        let learner_id = config.add_learner();
+        // The learner is neither counted in the quorum nor chosen as primary.
+        assert_eq!(2, config.quorum());
+        assert_eq!(vec![a_id, b_id], config.voter_ids());
+        assert_eq!(a_id, config.primary_id(2));
+        let learner_sm = Arc::new(Accumulator::new());
+        let replicas = [
+            Replica::new(
+                a_id,
+                config.clone(),
+                Arc::new(Accumulator::new()),
+                client_tx.clone(),
+                replica_tx.clone(),
+            ),
+            Replica::new(
+                b_id,
+                config.clone(),
+                Arc::new(Accumulator::new()),
+                client_tx.clone(),
+                replica_tx.clone(),
+            ),
+            Replica::new(
+                learner_id,
+                config.clone(),
+                learner_sm.clone(),
+                client_tx,
+                replica_tx.clone(),
+            ),
+        ];
+        let client = Client::new(config, replica_tx);
+        client.on_request(Op::Add(10), Box::new(|_| {}));
+        // The learner does not acknowledge the op, so it cannot commit it.
+        let mut held = Vec::new();
+        while let Ok((replica_id, message)) = replica_rx.try_recv() {
+            assert!(!matches!(
+                message,
+                Message::PrepareOk { replica_id, .. } if replica_id == learner_id
+            ));
+            if replica_id == b_id {
+                held.push(message);
+            } else {
+                replicas[replica_id].on_message(message);
+            }
+        }
+        assert!(client_rx.is_empty());
+        for message in held {
+            replicas[b_id].on_message(message);
+        }
+        while let Ok((replica_id, message)) = replica_rx.try_recv() {
+            replicas[replica_id].on_message(message);
+        }
+        assert_eq!(Ok(10), client_rx.try_recv().unwrap().result);
+        // The learner applies the op when the primary commits it.
+        replicas[a_id].on_idle();
+        while let Ok((replica_id, message)) = replica_rx.try_recv() {
+            replicas[replica_id].on_message(message);
+        }
+        assert_eq!(10, *learner_sm.accumulator.lock());
+        // The learner neither confirms the primary nor promises it a lease.
+        replicas[learner_id].on_message(Message::Heartbeat {
+            cluster_id: 0,
+            view_number: 0,
+            round: 1,
+        });
+        assert!(replica_rx.is_empty());
+        assert_eq!(0, replicas[learner_id].lease_promise());
+    }
+
+    #[test]
+    fn test_op_context() {
+        let _ = env_logger::try_init();
+        let (client_tx, _client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::new());
+        let sms: Vec<_> = (0..3).map(|_| Arc::new(Accumulator::new())).collect();
+        let replicas: Vec<_> = sms
+            .iter()
+            .map(|sm| {
+                Replica::new(
+                    config.add_replica(),
+                    config.clone(),
+                    sm.clone(),
+                    client_tx.clone(),
+                    replica_tx.clone(),
+                )
+            })
+            .collect();
+        let client = Client::new(config, replica_tx);
+        for _ in 0..3 {
+            client.on_request(Op::Add(1), Box::new(|_| {}));
+            while let Ok((replica_id, message)) = replica_rx.try_recv() {
+                replicas[replica_id].on_message(message);
+            }
+        }
+        replicas[0].on_idle();
+        while let Ok((replica_id, message)) = replica_rx.try_recv() {
+            replicas[replica_id].on_message(message);
+        }
+        // Every replica applies the ops with the contexts the primary
+        // assigned to them.
+        let contexts = sms[0].contexts.lock().clone();
+        assert_eq!(3, contexts.len());
+        assert!(contexts
+            .windows(2)
+            .all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        for sm in &sms[1..] {
+            assert_eq!(contexts, *sm.contexts.lock());
+        }
+    }
+
+    #[test]
+    fn test_op_seed_is_stable() {
+        #[derive(Debug)]
+        struct FixedClock;
+        impl Clock for FixedClock {
+            fn now(&self) -> u64 {
+                1000
+            }
+        }
+        let (client_tx, _client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, _replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::new());
+        let sm = Arc::new(Accumulator::new());
+        let mut replica = Replica::new(
+            config.add_replica(),
+            config,
+            sm.clone(),
+            client_tx,
+            replica_tx,
+        );
+        replica.set_clock(FixedClock);
+        replica.set_seed(42);
+        replica.on_message(Message::Request {
+            cluster_id: 0,
+            client_id: 0,
+            request_number: 0,
+            op: Op::Add(1),
+        });
+        // Op seeds must not change between builds, or replaying a log
+        // written by an older build diverges.
+        let context = sm.contexts.lock()[0];
+        assert_eq!(1000, context.timestamp);
+        assert_eq!(5036582344713433737, context.seed);
+    }
+
+    #[test]
+    fn test_validate() {
+        let _ = env_logger::try_init();
+        let (client_tx, client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::new());
+        config.add_replica();
+        config.add_replica();
+        let sm = Arc::new(Accumulator::new());
+        let replica_a = Replica::new(0, config.clone(), sm.clone(), client_tx, replica_tx);
+        replica_a.on_message(Message::Request {
+            cluster_id: 0,
+            client_id: 0,
+            request_number: 0,
+            op: Op::Sub(-1),
+        });
+        // A rejected op is never replicated.
+        assert!(replica_rx.is_empty());
+        assert_eq!(
+            Err(ReplyError::Rejected("negative subtrahend".to_string())),
+            client_rx.try_recv().unwrap().result
+        );
+        assert!(sm.contexts.lock().is_empty());
+    }
+
+    #[test]
+    fn test_apply_failure() {
+        let _ = env_logger::try_init();
+        let (client_tx, client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::new());
+        let sm = Arc::new(Accumulator::new());
+        let a_id = config.add_replica();
+        let replica = Replica::new(
+            a_id,
+            config.clone(),
+            sm.clone(),
+            client_tx,
+            replica_tx.clone(),
+        );
+        let client = Client::new(config, replica_tx);
+        let request = |op| {
+            client.on_request(op, Box::new(|_| {}));
+            let (_, message) = replica_rx.recv().unwrap();
+            replica.on_message(message);
+            client_rx.try_recv().ok().map(|reply| reply.result)
+        };
+        assert_eq!(Some(Ok(10)), request(Op::Add(10)));
+        // A deterministic error is the output of the op.
+        assert_eq!(
+            Some(Err(ReplyError::Failed("division by zero".to_string()))),
+            request(Op::Div(0))
+        );
+        assert_eq!(Some(Ok(5)), request(Op::Div(2)));
+        assert!(replica.is_healthy());
+        // A panic makes the replica stop applying ops.
+        assert_eq!(None, request(Op::Crash));
+        assert!(!replica.is_healthy());
+        assert_eq!(Some("crash".to_string()), replica.failure());
+        // New requests are refused rather than appended to the log.
+        assert_eq!(
+            Some(Err(ReplyError::Unhealthy("crash".to_string()))),
+            request(Op::Add(1))
+        );
+        assert_eq!(4, replica.op_number());
+        assert_eq!(5, *sm.accumulator.lock());
+    }
+
+    #[test]
+    fn test_async_apply() {
+        let _ = env_logger::try_init();
+        let (client_tx, client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::new());
+        let sm = Arc::new(Accumulator::new());
+        let a_id = config.add_replica();
+        let replica = Replica::with_async_apply(
+            a_id,
+            config.clone(),
+            sm.clone(),
+            client_tx,
+            replica_tx.clone(),
+        );
+        let client = Client::new(config, replica_tx);
+        // A blocked state machine does not block replication.
+        let accumulator = sm.accumulator.lock();
+        client.on_request(Op::Add(10), Box::new(|_| {}));
+        client.on_request(Op::Div(0), Box::new(|_| {}));
+        client.on_request(Op::Sub(3), Box::new(|_| {}));
+        client.on_query((), Box::new(|_| {}));
+        for _ in 0..4 {
+            let (_, message) = replica_rx.recv().unwrap();
+            replica.on_message(message);
+        }
+        assert!(client_rx.is_empty());
+        drop(accumulator);
+        // The executor applies the ops and executes the query in order.
+        let replies: Vec<_> = (0..4)
+            .map(|_| {
+                client_rx
+                    .recv_timeout(Duration::from_secs(10))
+                    .unwrap()
+                    .result
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                Ok(10),
+                Err(ReplyError::Failed("division by zero".to_string())),
+                Ok(7),
+                Ok(7)
+            ],
+            replies
+        );
+    }
+
+    #[test]
+    fn test_async_apply_failure() {
+        let _ = env_logger::try_init();
+        let (client_tx, client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::new());
+        let sm = Arc::new(Accumulator::new());
+        let a_id = config.add_replica();
+        let replica = Replica::with_async_apply(
+            a_id,
+            config.clone(),
+            sm.clone(),
+            client_tx,
+            replica_tx.clone(),
+        );
+        let client = Client::new(config, replica_tx);
+        // Commit the ops before the executor gets to them.
+        let accumulator = sm.accumulator.lock();
+        client.on_request(Op::Add(10), Box::new(|_| {}));
+        client.on_request(Op::Crash, Box::new(|_| {}));
+        client.on_request(Op::Add(1), Box::new(|_| {}));
+        client.on_query((), Box::new(|_| {}));
+        for _ in 0..4 {
+            let (_, message) = replica_rx.recv().unwrap();
+            replica.on_message(message);
+        }
+        drop(accumulator);
+        // The executor applies no op after the failed one, and refuses the
+        // query rather than answer it from a half-applied state.
+        let replies: Vec<_> = (0..2)
+            .map(|_| {
+                client_rx
+                    .recv_timeout(Duration::from_secs(10))
+                    .unwrap()
+                    .result
+            })
+            .collect();
+        assert_eq!(
+            vec![Ok(10), Err(ReplyError::Unhealthy("crash".to_string()))],
+            replies
+        );
+        assert!(client_rx.is_empty());
+        assert!(!replica.is_healthy());
+        // The commit number stops before the op that failed.
+        assert_eq!(3, replica.op_number());
+        assert_eq!(1, replica.commit_number());
+        assert_eq!(10, *sm.accumulator.lock());
+    }
+
+    #[test]
+    fn test_async_apply_state_transfer() {
+        let _ = env_logger::try_init();
+        let (client_tx, _client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let mut config = Config::new();
+        // Any gap sends the backup straight to state transfer.
+        config.prepare_buffer_size = 0;
+        let config = Arc::new(config);
+        let a_id = config.add_replica();
+        let b_id = config.add_replica();
+        let c_id = config.add_replica();
+        let sm = Arc::new(Accumulator::new());
+        let replica_a = Replica::with_async_apply(
+            a_id,
+            config.clone(),
+            sm.clone(),
+            client_tx.clone(),
+            replica_tx.clone(),
+        );
+        let replica_b = Replica::new(
+            b_id,
+            config,
+            Arc::new(Accumulator::new()),
+            client_tx,
+            replica_tx,
+        );
+        let deliver_to_b = || {
+            let messages: Vec<_> = replica_rx.try_iter().collect();
+            for (replica_id, message) in messages {
+                if replica_id == b_id {
+                    replica_b.on_message(message);
+                }
+            }
+        };
+        let request = |request_number, op| Message::Request {
+            cluster_id: 0,
+            client_id: 0,
+            request_number,
+            op,
+        };
+        // The primary commits an op before its executor applies it. `b`
+        // appends the op, but its `PrepareOk` message is lost, and it misses
+        // the `Prepare` message of the next op.
+        let accumulator = sm.accumulator.lock();
+        replica_a.on_message(request(0, Op::Add(10)));
+        deliver_to_b();
+        replica_rx.try_iter().for_each(drop);
+        replica_a.on_message(request(1, Op::Add(20)));
+        replica_a.on_message(Message::PrepareOk {
+            cluster_id: 0,
+            view_number: 0,
+            op_number: 1,
+            replica_id: c_id,
+        });
+        replica_rx.try_iter().for_each(drop);
+        // `b` commits the first op from the resent `Prepare` message.
+        replica_a.on_idle();
+        deliver_to_b();
+        assert_eq!(1, replica_b.commit_number());
+        replica_rx.try_iter().for_each(drop);
+        // State transfer from the primary agrees with `b` on what is
+        // committed.
+        replica_b.on_message(Message::Prepare {
+            cluster_id: 0,
+            view_number: 0,
+            op_number: 4,
+            entries: vec![LogEntry {
+                client_id: 0,
+                request_number: 3,
+                op: Op::Add(30),
+                context: OpContext::default(),
+            }],
+            commit_number: 1,
+        });
+        match replica_rx.try_recv().unwrap() {
+            (0, message @ Message::GetState { .. }) => replica_a.on_message(message),
+            (_, message) => panic!("unexpected message {:?}", message),
+        }
+        deliver_to_b();
+        assert_eq!(Status::Normal, replica_b.status());
+        assert_eq!(1, replica_b.commit_number());
+        drop(accumulator);
+    }
+
+    #[test]
+    fn test_config_file_format() {
+        let config: Config = "
+            # Test cluster
+            cluster_id = 42
+            replica 1 10.0.0.2:7000 zone=b
+            replica 0 10.0.0.1:7000 zone=a rack=3
+            replica 2 -
+            replica 3 10.0.1.1:7000 learner zone=d
+        "
+        .parse()
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(42, config.cluster_id);
+        assert_eq!(vec![0, 1, 2, 3], config.replica_ids());
+        assert_eq!(vec![0, 1, 2], config.voter_ids());
+        let replica = config.replica(0).unwrap();
+        assert_eq!("10.0.0.1:7000", replica.address);
+        assert_eq!("3", replica.metadata["rack"]);
+        assert_eq!("", config.replica(2).unwrap().address);
+        let learner = config.replica(3).unwrap();
+        assert_eq!(ReplicaRole::Learner, learner.role);
+        assert_eq!("d", learner.metadata["zone"]);
+        let reparsed: Config = config.to_string().parse().unwrap();
+        assert_eq!(*config.replicas.borrow(), *reparsed.replicas.borrow());
+        assert!(matches!(
+            "max_batch_size = x".parse::<Config>(),
+            Err(ConfigError::Parse { line: 1, .. })
+        ));
+        assert!(matches!(
+            "max_batch_sizes = 1".parse::<Config>(),
+            Err(ConfigError::Parse { line: 1, .. })
+        ));
+        assert!(matches!(
+            "replica x 10.0.0.1:7000".parse::<Config>(),
+            Err(ConfigError::Parse { line: 1, .. })
+        ));
+        assert!(matches!(
+            "cluster_id = 1\ncluster_id = 2".parse::<Config>(),
+            Err(ConfigError::Parse { line: 2, .. })
+        ));
+        assert!(matches!(
+            "replica 0 a zone=a zone=b".parse::<Config>(),
+            Err(ConfigError::Parse { line: 1, .. })
+        ));
+        assert!(matches!(
+            "replica 0 a zone=\"a".parse::<Config>(),
+            Err(ConfigError::Parse { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_config_file_format_settings() {
+        let mut config = Config::with_cluster_id(7);
+        config.add_replica();
+        config.prepare_buffer_size = 1;
+        config.prepare_gap_timeout = 2;
+        config.prepare_repair_timeout = 3;
+        config.state_transfer_timeout = 4;
+        config.max_batch_size = 5;
+        config.max_batch_bytes = 6;
+        config.max_batch_delay = 7;
+        config.max_inflight_ops = 8;
+        config.max_queued_requests = 9;
+        config.lease_duration = Some(10);
+        config.lease_clock_drift = 11;
+        config.max_queued_queries = 12;
+        config.query_timeout = 13;
+        config.stale_read_timeout = 14;
+        let reparsed: Config = config.to_string().parse().unwrap();
+        assert_eq!(format!("{:?}", config), format!("{:?}", reparsed));
+        config.lease_duration = None;
+        let reparsed: Config = config.to_string().parse().unwrap();
+        assert_eq!(None, reparsed.lease_duration);
+        // Settings that are missing keep their defaults.
+        let config: Config = "max_batch_size = 4\nreplica 0 -".parse().unwrap();
+        assert_eq!(4, config.max_batch_size);
+        assert_eq!(
+            crate::config::DEFAULT_MAX_QUEUED_REQUESTS,
+            config.max_queued_requests
+        );
+    }
+
+    #[test]
+    fn test_config_file_format_quoting() {
+        let config = Config::new();
+        let id = config.add_replica_with_address("host name:7000");
+        config.replicas.borrow_mut()[id]
+            .metadata
+            .insert("zone".to_string(), "us east \"1\" \\ a".to_string());
+        config.replicas.borrow_mut()[id]
+            .metadata
+            .insert("rack".to_string(), "".to_string());
+        let reparsed: Config = config.to_string().parse().unwrap();
+        assert_eq!(*config.replicas.borrow(), *reparsed.replicas.borrow());
+        let config: Config = "replica 0 - zone=\"us east\"".parse().unwrap();
+        assert_eq!("us east", config.replica(0).unwrap().metadata["zone"]);
+    }
+
+    #[test]
+    fn test_config_validate() {
+        let config = Config::new();
+        assert!(matches!(config.validate(), Err(ConfigError::Empty)));
+        let config: Config = "replica 0 a\nreplica 0 b".parse().unwrap();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::DuplicateReplica(0))
+        ));
+        let config: Config = "replica 0 a learner".parse().unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::NoVoters)));
+        let config: Config = "replica 0 a\nreplica 2 b".parse().unwrap();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::MissingReplica(1))
+        ));
+        let config = Config::new();
+        let id = config.add_replica();
+        config.replicas.borrow_mut()[id]
+            .metadata
+            .insert("a zone".to_string(), "a".to_string());
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidMetadataKey(0, _))
+        ));
+        let mut config = Config::new();
+        config.add_replica();
+        config.prepare_repair_timeout = 0;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidSetting {
+                setting: "prepare_repair_timeout",
+                ..
+            })
+        ));
+        config.prepare_repair_timeout = 1;
+        config.state_transfer_timeout = 0;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidSetting {
+                setting: "state_transfer_timeout",
+                ..
+            })
+        ));
+        config.state_transfer_timeout = 1;
+        config.max_batch_size = 0;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidSetting {
+                setting: "max_batch_size",
+                ..
+            })
+        ));
+        config.max_batch_size = 8;
+        config.max_inflight_ops = 0;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidSetting {
+                setting: "max_inflight_ops",
+                ..
+            })
+        ));
+        config.max_inflight_ops = 1;
+        config.max_queued_requests = 0;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidSetting {
+                setting: "max_queued_requests",
+                ..
+            })
+        ));
+        config.max_queued_requests = 4;
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidSetting {
+                setting: "max_batch_size",
+                ..
+            })
+        ));
+        config.max_queued_requests = 8;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_cluster_id_mismatch() {
+        let _ = env_logger::try_init();
+        let (client_tx, _client_rx) = crossbeam_channel::unbounded();
+        let (replica_tx, replica_rx) = crossbeam_channel::unbounded();
+        let config = Arc::new(Config::with_cluster_id(1));
+        let sm = Arc::new(Accumulator::new());
+        let a_id = config.add_replica();
+        let replica = Replica::new(a_id, config, sm.clone(), client_tx, replica_tx.clone());
+        let other_config = Arc::new(Config::with_cluster_id(2));
+        other_config.add_replica();
+        let client = Client::new(other_config, replica_tx);
+        client.on_request(Op::Add(10), Box::new(|_| {}));
+        while !replica_rx.is_empty() {
+            let (_, message) = replica_rx.recv().unwrap();
+            replica.on_message(message);
+        }
+        assert_eq!(0, *sm.accumulator.lock());
+    }
+
     #[derive(Clone, Debug)]
     enum Op {
         Add(i32),
         Sub(i32),
+        Div(i32),
+        Crash,
     }
 
     struct Accumulator {
         accumulator: Mutex<i32>,
+        contexts: Mutex<Vec<OpContext>>,
     }
 
     impl Accumulator {
         fn new() -> Accumulator {
             let accumulator = Mutex::new(0);
-            Accumulator { accumulator }
+            let contexts = Mutex::new(Vec::new());
+            Accumulator {
+                accumulator,
+                contexts,
+            }
         }
     }
 
     impl StateMachine for Accumulator {
         type Input = Op;
-        type Output = ();
+        type Output = i32;
+        type Query = ();
 
-        fn apply(&self, op: Op) {
+        fn apply(&self, op: Op) -> i32 {
             let mut accumulator = self.accumulator.lock();
             match op {
                 Op::Add(value) => {
@@ -211,7 +1422,35 @@ mod tests {
                 Op::Sub(value) => {
                     *accumulator -= value;
                 }
+                Op::Div(value) => {
+                    *accumulator /= value;
+                }
+                Op::Crash => panic!("crash"),
             }
+            *accumulator
+        }
+
+        fn try_apply(&self, op: Op, context: &OpContext) -> Result<i32, ApplyError> {
+            match op {
+                Op::Div(0) => Err(ApplyError::Deterministic("division by zero".to_string())),
+                op => Ok(self.apply_with_context(op, context)),
+            }
+        }
+
+        fn validate(&self, op: &Op) -> Result<(), String> {
+            match op {
+                Op::Sub(value) if *value < 0 => Err("negative subtrahend".to_string()),
+                _ => Ok(()),
+            }
+        }
+
+        fn apply_with_context(&self, op: Op, context: &OpContext) -> i32 {
+            self.contexts.lock().push(*context);
+            self.apply(op)
+        }
+
+        fn query(&self, _query: ()) -> Result<i32, String> {
+            Ok(*self.accumulator.lock())
         }
     }
 }
@@ -1,35 +1,82 @@
-use crate::types::{ClientID, CommitID, OpNumber, ReplicaID, RequestNumber, ViewNumber};
+use crate::types::{ClientID, ClusterID, CommitID, OpNumber, ReplicaID, RequestNumber, ViewNumber};
 use std::fmt::Debug;
 
 #[derive(Clone, Debug)]
-pub enum Message<Op>
+pub enum Message<Op, Query = Op>
 where
     Op: Clone + Debug + Send,
+    Query: Clone + Debug + Send,
 {
     Request {
+        cluster_id: ClusterID,
         client_id: ClientID,
         request_number: RequestNumber,
         op: Op,
     },
+    /// The primary sends a `Prepare` message to replicate a batch of ops.
     Prepare {
+        cluster_id: ClusterID,
         view_number: ViewNumber,
+        /// The op number of the last entry in the batch. The entries have
+        /// consecutive op numbers.
         op_number: OpNumber,
-        op: Op,
+        /// The batch of log entries to replicate.
+        entries: Vec<LogEntry<Op>>,
         commit_number: CommitID,
     },
+    /// The client sends a `Query` message to execute a read-only query
+    /// without appending it to the log. Linearizable queries go to the
+    /// primary, bounded-staleness queries can go to any replica.
+    Query {
+        cluster_id: ClusterID,
+        client_id: ClientID,
+        request_number: RequestNumber,
+        query: Query,
+        consistency: ReadConsistency,
+    },
     PrepareOk {
+        cluster_id: ClusterID,
         view_number: ViewNumber,
         op_number: OpNumber,
+        /// The ID of the replica that acknowledges the op.
+        replica_id: ReplicaID,
     },
     Commit {
+        cluster_id: ClusterID,
         view_number: ViewNumber,
         commit_number: CommitID,
     },
+    /// The primary sends a `Heartbeat` message to confirm that it is still
+    /// the primary before it answers queries.
+    Heartbeat {
+        cluster_id: ClusterID,
+        view_number: ViewNumber,
+        /// The heartbeat round, which increases with every heartbeat.
+        round: u64,
+    },
+    HeartbeatOk {
+        cluster_id: ClusterID,
+        view_number: ViewNumber,
+        round: u64,
+        /// The ID of the replica that acknowledges the heartbeat.
+        replica_id: ReplicaID,
+    },
     GetState {
+        cluster_id: ClusterID,
         replica_id: ReplicaID,
         view_number: ViewNumber,
         op_number: OpNumber,
     },
+    /// A backup sends a `GetPrepares` message to the primary to ask for the
+    /// ops that are missing between its log and the out-of-order `Prepare`
+    /// messages it has buffered. The primary resends the ops as `Prepare`
+    /// messages.
+    GetPrepares {
+        cluster_id: ClusterID,
+        replica_id: ReplicaID,
+        view_number: ViewNumber,
+        op_numbers: Vec<OpNumber>,
+    },
     /// The NewState message is sent to a replica to repair it.
     ///
     /// We differ from the paper by not just sending the op number of the last
@@ -37,10 +84,12 @@ where
     /// us to verify that we're repairing the right part of the log in the
     /// replica.
     NewState {
+        /// The ID of the cluster the sending replica belongs to.
+        cluster_id: ClusterID,
         /// The view number of the replica that is sending the NewState message.
         view_number: ViewNumber,
         /// The log of operations that this replica needs to apply to catch up.
-        log: Vec<Op>,
+        log: Vec<LogEntry<Op>>,
         /// The op number of the first entry in the log. This is the op number
         /// that we requested in the GetState message.
         op_number_start: OpNumber,
@@ -50,3 +99,92 @@ where
         commit_number: CommitID,
     },
 }
+
+impl<Op, Query> Message<Op, Query>
+where
+    Op: Clone + Debug + Send,
+    Query: Clone + Debug + Send,
+{
+    /// Returns the ID of the cluster this message was sent in.
+    pub fn cluster_id(&self) -> ClusterID {
+        match self {
+            Message::Request { cluster_id, .. }
+            | Message::Prepare { cluster_id, .. }
+            | Message::Query { cluster_id, .. }
+            | Message::PrepareOk { cluster_id, .. }
+            | Message::Commit { cluster_id, .. }
+            | Message::Heartbeat { cluster_id, .. }
+            | Message::HeartbeatOk { cluster_id, .. }
+            | Message::GetState { cluster_id, .. }
+            | Message::GetPrepares { cluster_id, .. }
+            | Message::NewState { cluster_id, .. } => *cluster_id,
+        }
+    }
+}
+
+/// The consistency a read-only query requires.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReadConsistency {
+    /// The query observes every op committed before it was sent. Only the
+    /// primary answers linearizable queries.
+    Linearizable,
+    /// The query observes at least the ops up to the given commit number.
+    MinCommit(CommitID),
+    /// The query observes a state that lags the commit number of the
+    /// primary, as last seen by the replica, by at most the given number
+    /// of ops.
+    MaxLag(usize),
+}
+
+/// Nondeterministic inputs the primary assigns to an op, so that every
+/// replica applies the op with the same values.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpContext {
+    /// Cluster timestamp in milliseconds since the Unix epoch. Timestamps
+    /// never go backwards in log order.
+    pub timestamp: u64,
+    /// Random seed.
+    pub seed: u64,
+}
+
+/// An entry in the replicated log: an op, the client request it
+/// originates from, and the context the primary assigned to it.
+#[derive(Clone, Debug)]
+pub struct LogEntry<Op> {
+    pub client_id: ClientID,
+    pub request_number: RequestNumber,
+    pub op: Op,
+    pub context: OpContext,
+}
+
+/// A reply the primary sends to a client when its request completes.
+#[derive(Clone, Debug)]
+pub struct Reply<Output> {
+    pub view_number: ViewNumber,
+    pub client_id: ClientID,
+    pub request_number: RequestNumber,
+    pub result: Result<Output, ReplyError>,
+}
+
+/// The reason why a request was not executed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplyError {
+    /// The primary has too many requests in flight. The client should retry
+    /// the request later.
+    Busy,
+    /// The state machine rejected the op before it was appended to the log.
+    Rejected(String),
+    /// The state machine failed to apply the op. The failure is
+    /// deterministic, so every replica applied the op with the same result.
+    Failed(String),
+    /// The replica is not the primary, so it cannot answer a linearizable
+    /// query. The client should send the query to the primary of the view
+    /// in the reply.
+    NotPrimary,
+    /// The query waited longer than `Config::query_timeout`.
+    Timeout,
+    /// The replica failed to apply an op non-deterministically, so it
+    /// refuses new requests and queries. A refused request never entered
+    /// the log.
+    Unhealthy(String),
+}
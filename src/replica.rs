@@ -1,22 +1,178 @@
 use crate::config::Config;
-use crate::message::Message;
-use crate::state_machine::StateMachine;
-use crate::types::{CommitID, OpNumber, ReplicaID, ViewNumber};
-use crossbeam_channel::Sender;
-use log::trace;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use crate::message::{LogEntry, Message, OpContext, ReadConsistency, Reply, ReplyError};
+use crate::state_machine::{ApplyError, StateMachine};
+use crate::types::{ClientID, CommitID, OpNumber, ReplicaID, RequestNumber, ViewNumber};
+use crossbeam_channel::{Receiver, Sender};
+use log::{error, trace, warn};
+use parking_lot::Mutex;
+use std::cell::{Ref, RefCell};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::hash::{BuildHasher, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the timestamps the primary assigns to ops.
+pub trait Clock: Debug + Send + Sync {
+    /// Returns the current time in milliseconds since the Unix epoch.
+    fn now(&self) -> u64;
+}
+
+/// The system wall clock.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_millis() as u64)
+    }
+}
+
+/// A message between replicas and clients of state machine `SM`.
+pub(crate) type SmMessage<SM> = Message<<SM as StateMachine>::Input, <SM as StateMachine>::Query>;
 
 /// Replica status.
-#[derive(Debug, PartialEq)]
-enum Status {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
     Normal,
     Recovery,
 }
 
+/// `Prepare` messages that a backup received ahead of a gap in its log.
+#[derive(Debug)]
+struct PrepareBuffer<Op> {
+    /// Buffered log entries and the primary's commit number, keyed by op number.
+    prepares: BTreeMap<OpNumber, (LogEntry<Op>, CommitID)>,
+    /// The tick at which the current gap was first observed.
+    gap_since: Option<u64>,
+    /// The tick at which we asked the primary for the missing ops.
+    repair_requested: Option<u64>,
+}
+
+impl<Op> PrepareBuffer<Op> {
+    fn new() -> PrepareBuffer<Op> {
+        PrepareBuffer {
+            prepares: BTreeMap::default(),
+            gap_since: None,
+            repair_requested: None,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.prepares.clear();
+        self.gap_since = None;
+        self.repair_requested = None;
+    }
+}
+
+/// Client requests that the primary has not yet replicated.
+#[derive(Debug)]
+struct Batch<Op> {
+    entries: Vec<LogEntry<Op>>,
+    /// The total size of the ops in the batch in bytes.
+    bytes: usize,
+    /// The tick at which the first request in the batch arrived.
+    since: u64,
+}
+
+impl<Op> Batch<Op> {
+    fn new() -> Batch<Op> {
+        Batch {
+            entries: Vec::default(),
+            bytes: 0,
+            since: 0,
+        }
+    }
+}
+
+/// A query that waits for the primary to confirm it is still the primary
+/// and to commit every op that was in its log when the query arrived.
+#[derive(Debug)]
+struct PendingQuery<Query> {
+    client_id: ClientID,
+    request_number: RequestNumber,
+    query: Query,
+    /// The op number of the primary when the query arrived.
+    op_number: OpNumber,
+    /// The heartbeat round started when the query arrived.
+    round: u64,
+    /// The tick at which the query arrived.
+    arrived: u64,
+}
+
+/// A bounded-staleness query that waits for the replica to catch up.
+#[derive(Debug)]
+struct StaleQuery<Query> {
+    client_id: ClientID,
+    request_number: RequestNumber,
+    query: Query,
+    consistency: ReadConsistency,
+    /// The tick at which the query arrived.
+    arrived: u64,
+}
+
+/// Work for the apply executor, which processes it in order.
+#[derive(Debug)]
+enum ApplyTask<Op, Query> {
+    /// Apply a committed op, and reply to the client if `respond` is set.
+    Apply {
+        view_number: ViewNumber,
+        op_number: OpNumber,
+        entry: LogEntry<Op>,
+        respond: bool,
+    },
+    /// Execute a read-only query after every op queued before it.
+    Query {
+        view_number: ViewNumber,
+        client_id: ClientID,
+        request_number: RequestNumber,
+        query: Query,
+    },
+}
+
+/// A heartbeat round that a quorum has not yet acknowledged.
+#[derive(Debug)]
+struct HeartbeatRound {
+    /// The tick at which the round started.
+    started: u64,
+    /// Replicas that have acknowledged the round.
+    acks: HashSet<ReplicaID>,
+}
+
+/// Read-only queries waiting on the primary and the heartbeat rounds that
+/// confirm the primary is still the primary.
+#[derive(Debug)]
+struct Reads<Query> {
+    pending: VecDeque<PendingQuery<Query>>,
+    stale: Vec<StaleQuery<Query>>,
+    /// The last heartbeat round we started.
+    round: u64,
+    /// Heartbeat rounds that a quorum has not yet acknowledged.
+    rounds: BTreeMap<u64, HeartbeatRound>,
+    /// The last heartbeat round that a quorum acknowledged.
+    confirmed_round: u64,
+    /// The tick at which our leader lease expires.
+    lease_expiry: u64,
+}
+
+impl<Query> Reads<Query> {
+    fn new() -> Reads<Query> {
+        Reads {
+            pending: VecDeque::default(),
+            stale: Vec::default(),
+            round: 0,
+            rounds: BTreeMap::default(),
+            confirmed_round: 0,
+            lease_expiry: 0,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Replica<SM: StateMachine> {
     config: Arc<Config>,
@@ -24,12 +180,41 @@ pub struct Replica<SM: StateMachine> {
     state_machine: Arc<SM>,
     status: RefCell<Status>,
     view_number: ViewNumber,
+    /// The number of committed ops, including those the apply executor has
+    /// not applied yet.
     commit_number: AtomicUsize,
+    /// The number of committed ops applied to the state machine. We report
+    /// and serve reads at this commit number, so an op that fails to apply
+    /// is never reported as committed.
+    applied_number: Arc<AtomicUsize>,
+    /// The highest commit number of the primary we have seen.
+    primary_commit_number: AtomicUsize,
+    /// The tick at which we last heard from the primary.
+    primary_contact: AtomicU64,
     op_number: AtomicUsize,
-    log: RefCell<Vec<SM::Input>>,
-    acks: RefCell<HashMap<ReplicaID, usize>>,
-    client_tx: Sender<SM::Output>,
-    replica_tx: Sender<(ReplicaID, Message<SM::Input>)>,
+    log: RefCell<Vec<LogEntry<SM::Input>>>,
+    /// The highest op number each replica has acknowledged, keyed by replica ID.
+    acks: RefCell<HashMap<ReplicaID, OpNumber>>,
+    prepare_buffer: RefCell<PrepareBuffer<SM::Input>>,
+    batch: RefCell<Batch<SM::Input>>,
+    reads: RefCell<Reads<SM::Query>>,
+    /// Number of ticks (calls to `on_idle`) since the replica started.
+    ticks: AtomicU64,
+    /// The tick at which we last sent a `GetState` message.
+    state_transfer_tick: AtomicU64,
+    /// The tick until which we promised the primary not to start a view change.
+    lease_promise: AtomicU64,
+    /// Clock from which the primary takes op timestamps.
+    clock: Box<dyn Clock>,
+    /// Key from which the primary derives op seeds.
+    seed_key: u64,
+    /// Why applying an op failed non-deterministically, if it did. An
+    /// unhealthy replica does not apply any more ops.
+    failure: Arc<Mutex<Option<String>>>,
+    /// Queue of the apply executor, if ops are applied off-thread.
+    apply_tx: Option<Sender<ApplyTask<SM::Input, SM::Query>>>,
+    client_tx: Sender<Reply<SM::Output>>,
+    replica_tx: Sender<(ReplicaID, SmMessage<SM>)>,
 }
 
 impl<SM: StateMachine> Replica<SM> {
@@ -37,15 +222,27 @@ impl<SM: StateMachine> Replica<SM> {
         self_id: ReplicaID,
         config: Arc<Config>,
         state_machine: Arc<SM>,
-        client_tx: Sender<SM::Output>,
-        replica_tx: Sender<(ReplicaID, Message<SM::Input>)>,
+        client_tx: Sender<Reply<SM::Output>>,
+        replica_tx: Sender<(ReplicaID, SmMessage<SM>)>,
     ) -> Replica<SM> {
         let status = RefCell::new(Status::Normal);
         let view_number = 0;
         let commit_number = AtomicUsize::new(0);
+        let applied_number = Arc::new(AtomicUsize::new(0));
+        let primary_commit_number = AtomicUsize::new(0);
+        let primary_contact = AtomicU64::new(0);
         let op_number = AtomicUsize::new(0);
         let log = RefCell::new(Vec::default());
         let acks = RefCell::new(HashMap::default());
+        let prepare_buffer = RefCell::new(PrepareBuffer::new());
+        let batch = RefCell::new(Batch::new());
+        let reads = RefCell::new(Reads::new());
+        let ticks = AtomicU64::new(0);
+        let state_transfer_tick = AtomicU64::new(0);
+        let lease_promise = AtomicU64::new(0);
+        let clock = Box::new(SystemClock);
+        let seed_key = RandomState::new().build_hasher().finish();
+        let failure = Arc::new(Mutex::new(None));
         Replica {
             self_id,
             config,
@@ -53,54 +250,116 @@ impl<SM: StateMachine> Replica<SM> {
             status,
             view_number,
             commit_number,
+            applied_number,
+            primary_commit_number,
+            primary_contact,
             op_number,
             log,
             acks,
+            prepare_buffer,
+            batch,
+            reads,
+            ticks,
+            state_transfer_tick,
+            lease_promise,
+            clock,
+            seed_key,
+            failure,
+            apply_tx: None,
             client_tx,
             replica_tx,
         }
     }
 
     /// The main entry point to replica logic.
-    pub fn on_message(&self, message: Message<SM::Input>) {
+    pub fn on_message(&self, message: SmMessage<SM>) {
         trace!("Replica {} <- {:?}", self.self_id, message);
+        if message.cluster_id() != self.config.cluster_id {
+            warn!(
+                "Replica {} dropping message from cluster {}",
+                self.self_id,
+                message.cluster_id()
+            );
+            return;
+        }
         match message {
-            Message::Request { op, .. } => {
-                self.on_request(op);
+            Message::Request {
+                client_id,
+                request_number,
+                op,
+                ..
+            } => {
+                self.on_request(client_id, request_number, op);
+            }
+            Message::Query {
+                client_id,
+                request_number,
+                query,
+                consistency,
+                ..
+            } => {
+                self.on_query(client_id, request_number, query, consistency);
             }
             Message::Prepare {
                 view_number,
                 op_number,
-                op,
+                entries,
                 commit_number,
+                ..
             } => {
-                self.on_prepare(view_number, op_number, op, commit_number);
+                self.on_prepare(view_number, op_number, entries, commit_number);
             }
             Message::PrepareOk {
                 view_number,
                 op_number,
+                replica_id,
+                ..
             } => {
-                self.on_prepare_ok(view_number, op_number);
+                self.on_prepare_ok(view_number, op_number, replica_id);
             }
             Message::Commit {
                 view_number,
                 commit_number,
+                ..
             } => {
                 self.on_commit(view_number, commit_number);
             }
+            Message::Heartbeat {
+                view_number, round, ..
+            } => {
+                self.on_heartbeat(view_number, round);
+            }
+            Message::HeartbeatOk {
+                view_number,
+                round,
+                replica_id,
+                ..
+            } => {
+                self.on_heartbeat_ok(view_number, round, replica_id);
+            }
             Message::GetState {
                 replica_id,
                 view_number,
                 op_number,
+                ..
             } => {
                 self.on_get_state(replica_id, view_number, op_number);
             }
+            Message::GetPrepares {
+                replica_id,
+                view_number,
+                op_numbers,
+                ..
+            } => {
+                self.on_get_prepares(replica_id, view_number, op_numbers);
+            }
             Message::NewState {
                 view_number,
                 log,
                 op_number_start,
                 op_number_end,
                 commit_number,
+                ..
             } => {
                 self.on_new_state(
                     view_number,
@@ -113,86 +372,649 @@ impl<SM: StateMachine> Replica<SM> {
         }
     }
 
-    /// The client sends a `Request` message to the primary, which replicates
-    /// the operation to the other replicas.
-    fn on_request(&self, op: SM::Input) {
+    /// The client sends a `Request` message to the primary, which validates
+    /// the op, collects requests into a batch and replicates the batch to
+    /// the other replicas.
+    fn on_request(&self, client_id: ClientID, request_number: RequestNumber, op: SM::Input) {
         // TODO: If not primary, drop request, advise client to connect to primary.
         assert!(self.is_primary());
         // TODO: If not in normal status, drop request, advise client to try later.
         assert_eq!(*self.status.borrow(), Status::Normal);
-        // Append operation to our log.
-        self.append_to_log(op.clone());
-        // And then register our own acknowledgement.
-        let op_number = self.op_number();
-        let mut acks = self.acks.borrow_mut();
-        acks.insert(op_number, 1);
         // TODO: Update client_table
+        // An unhealthy primary can no longer commit ops, so it does not
+        // accept new ones.
+        if let Some(reason) = self.failure() {
+            self.respond_with_error(client_id, request_number, ReplyError::Unhealthy(reason));
+            return;
+        }
+        if let Err(reason) = self.state_machine.validate(&op) {
+            self.respond_to_client(Reply {
+                view_number: self.view_number,
+                client_id,
+                request_number,
+                result: Err(ReplyError::Rejected(reason)),
+            });
+            return;
+        }
+        let mut batch = self.batch.borrow_mut();
+        // If the queue of requests waiting for the pipeline is full, tell
+        // the client to back off.
+        if batch.entries.len() >= self.config.max_queued_requests {
+            drop(batch);
+            self.respond_to_client(Reply {
+                view_number: self.view_number,
+                client_id,
+                request_number,
+                result: Err(ReplyError::Busy),
+            });
+            return;
+        }
+        if batch.entries.is_empty() {
+            batch.since = self.ticks();
+        }
+        batch.bytes += self.state_machine.input_size(&op);
+        batch.entries.push(LogEntry {
+            client_id,
+            request_number,
+            op,
+            context: OpContext::default(),
+        });
+        drop(batch);
+        self.prepare_batches();
+    }
+
+    /// Replicates batches of queued requests for as long as a batch is ready
+    /// and the pipeline has room for more uncommitted ops.
+    fn prepare_batches(&self) {
+        loop {
+            let inflight = self.op_number() - self.committed();
+            let window = self.config.max_inflight_ops.saturating_sub(inflight);
+            if window == 0 || !self.batch_ready() {
+                break;
+            }
+            self.prepare_batch(window);
+        }
+    }
+
+    /// A batch is ready when it is full or it has waited long enough.
+    fn batch_ready(&self) -> bool {
+        let batch = self.batch.borrow();
+        !batch.entries.is_empty()
+            && (batch.entries.len() >= self.config.max_batch_size
+                || batch.bytes >= self.config.max_batch_bytes
+                || self.ticks() - batch.since >= self.config.max_batch_delay)
+    }
+
+    /// Appends a batch of at most `window` queued requests to the log and
+    /// sends it to the other replicas in a single `Prepare` message.
+    fn prepare_batch(&self, window: usize) {
+        let mut entries: Vec<_> = {
+            let mut batch = self.batch.borrow_mut();
+            let mut len = 0;
+            let mut bytes = 0;
+            for entry in batch.entries.iter() {
+                if len == window.min(self.config.max_batch_size)
+                    || (len > 0 && bytes >= self.config.max_batch_bytes)
+                {
+                    break;
+                }
+                len += 1;
+                bytes += self.state_machine.input_size(&entry.op);
+            }
+            batch.bytes -= bytes;
+            // The requests left behind start waiting for a new batch.
+            batch.since = self.ticks();
+            batch.entries.drain(..len).collect()
+        };
+        // Assign contexts to the operations and append them to our log.
+        for entry in &mut entries {
+            entry.context = self.next_op_context();
+            self.append_to_log(entry.clone());
+        }
         // Send a prepare message to all the replicas.
         let view_number = self.view_number;
-        let commit_number = self.commit_number();
+        let commit_number = self.committed();
         self.send_msg_to_others(Message::Prepare {
+            cluster_id: self.config.cluster_id,
             view_number,
-            op,
-            op_number,
+            op_number: self.op_number(),
+            entries,
             commit_number,
         });
+        // If we are the only replica, our own acknowledgement is already a
+        // quorum, so commit right away, which can complete pending queries.
+        self.commit_acked_ops();
+        self.execute_queries();
     }
 
-    /// The primary sends a `Prepare` message to replicate an operation to backup
-    /// nodes. The nodes that receive a `Prepare` message will reply with `PrepareOk`
-    /// when they have appended `op` to their logs. The message also contains the
-    /// commit number of the primary, so that the backups can commit their logs up
-    /// to that point.
+    /// The primary sends a `Prepare` message to replicate a batch of operations
+    /// to backup nodes. The nodes that receive a `Prepare` message will reply
+    /// with `PrepareOk` when they have appended the operations to their logs.
+    /// The message also contains the commit number of the primary, so that the
+    /// backups can commit their logs up to that point.
     fn on_prepare(
         &self,
         view_number: ViewNumber,
         op_number: OpNumber,
-        op: SM::Input,
+        entries: Vec<LogEntry<SM::Input>>,
         commit_number: CommitID,
     ) {
         assert!(!self.is_primary());
         // TODO: If view number is not the same, initiate recovery.
         assert_eq!(self.view_number, view_number);
+        // The entries end at `op_number`, so there can be at most that many.
+        if entries.is_empty() || entries.len() > op_number {
+            warn!(
+                "Replica {} dropping Prepare for op {} with {} entries",
+                self.self_id,
+                op_number,
+                entries.len()
+            );
+            return;
+        }
+        self.primary_commit_number
+            .fetch_max(commit_number, Ordering::SeqCst);
+        self.primary_contact.store(self.ticks(), Ordering::SeqCst);
         if op_number <= self.op_number() {
-            return; // duplicate
+            // A duplicate, but acknowledge it again in case our `PrepareOk`
+            // message was lost.
+            if *self.status.borrow() == Status::Normal {
+                self.send_prepare_ok();
+            }
+            return;
         }
-        // If we fell behind in the log, initiate state transfer.
-        if op_number > self.op_number() + 1 {
-            self.state_transfer();
+        let first_op_number = op_number + 1 - entries.len();
+        // If we fell behind in the log, or are in the middle of state
+        // transfer, buffer the message until the gap fills.
+        if *self.status.borrow() == Status::Recovery || first_op_number > self.op_number() + 1 {
+            self.buffer_prepare(first_op_number, entries, commit_number);
             return;
         }
-        assert_eq!(self.op_number() + 1, op_number);
+        // Skip the entries that are already in our log.
+        let skip = self.op_number() + 1 - first_op_number;
+        for entry in entries.into_iter().skip(skip) {
+            self.append_prepared_op(entry, commit_number);
+        }
+        self.append_buffered_prepares();
+        // Acknowledge all the ops we have to the primary.
+        self.send_prepare_ok();
+        self.execute_queries();
+    }
+
+    /// Appends an op received in a `Prepare` message to our log.
+    fn append_prepared_op(&self, entry: LogEntry<SM::Input>, commit_number: CommitID) {
         // Append op to our log.
-        self.append_to_log(op);
+        self.append_to_log(entry);
         // Commit the log up to the commit number received in `Prepare`
         // message, which represents the committed state of the primary.
-        for op_idx in self.commit_number()..commit_number {
-            self.commit_op(op_idx);
+        // A resent `Prepare` can carry a commit number that is ahead of
+        // our log, so we only commit what we have.
+        let commit_number = commit_number.min(self.op_number());
+        self.commit_ops(commit_number);
+    }
+
+    /// Sends a `PrepareOk` message to the primary. The message acknowledges
+    /// every op up to our op number, so a lost `PrepareOk` is covered by the
+    /// next one.
+    fn send_prepare_ok(&self) {
+        // Learners do not count towards the quorum.
+        if !self.config.is_voter(self.self_id) {
+            return;
         }
-        // Acknowledge the `Prepare` message to the primary.
         self.send_msg_to_primary(Message::PrepareOk {
-            view_number,
-            op_number,
+            cluster_id: self.config.cluster_id,
+            view_number: self.view_number,
+            op_number: self.op_number(),
+            replica_id: self.self_id,
         });
     }
 
+    /// Buffers the entries of a `Prepare` message that arrived ahead of a gap
+    /// in our log. If the buffer is full, we fall back to state transfer.
+    fn buffer_prepare(
+        &self,
+        first_op_number: OpNumber,
+        entries: Vec<LogEntry<SM::Input>>,
+        commit_number: CommitID,
+    ) {
+        let mut buffer = self.prepare_buffer.borrow_mut();
+        if buffer.prepares.len() + entries.len() > self.config.prepare_buffer_size {
+            if *self.status.borrow() == Status::Normal {
+                buffer.clear();
+                drop(buffer);
+                self.state_transfer();
+            }
+            return;
+        }
+        for (op_number, entry) in (first_op_number..).zip(entries) {
+            buffer.prepares.insert(op_number, (entry, commit_number));
+        }
+        if buffer.gap_since.is_none() {
+            buffer.gap_since = Some(self.ticks());
+        }
+        let repair = *self.status.borrow() == Status::Normal
+            && buffer.repair_requested.is_none()
+            && self.config.prepare_gap_timeout == 0;
+        drop(buffer);
+        if repair {
+            self.repair_prepare_gap();
+        }
+    }
+
+    /// Appends buffered ops that follow our log without a gap.
+    fn append_buffered_prepares(&self) {
+        let mut appended = false;
+        loop {
+            let mut buffer = self.prepare_buffer.borrow_mut();
+            // Drop ops that are already in our log.
+            let next_op_number = self.op_number() + 1;
+            buffer.prepares = buffer.prepares.split_off(&next_op_number);
+            let Some((entry, commit_number)) = buffer.prepares.remove(&next_op_number) else {
+                break;
+            };
+            drop(buffer);
+            self.append_prepared_op(entry, commit_number);
+            appended = true;
+        }
+        let mut buffer = self.prepare_buffer.borrow_mut();
+        if buffer.prepares.is_empty() {
+            buffer.clear();
+        } else if appended {
+            // A gap filled, but there is another one after it.
+            buffer.gap_since = Some(self.ticks());
+            buffer.repair_requested = None;
+        }
+    }
+
+    /// If a gap in our log has not filled in time, ask the primary for the
+    /// missing ops. If they do not arrive in time, fall back to state
+    /// transfer. If state transfer itself does not complete in time, the
+    /// `GetState` or `NewState` message was lost, so we retry it.
+    fn repair_prepare_gap(&self) {
+        if *self.status.borrow() == Status::Recovery {
+            let elapsed = self.ticks() - self.state_transfer_tick.load(Ordering::SeqCst);
+            if elapsed >= self.config.state_transfer_timeout {
+                self.state_transfer();
+            }
+            return;
+        }
+        let mut buffer = self.prepare_buffer.borrow_mut();
+        let Some(gap_since) = buffer.gap_since else {
+            return;
+        };
+        if let Some(requested) = buffer.repair_requested {
+            if self.ticks() - requested >= self.config.prepare_repair_timeout {
+                buffer.clear();
+                drop(buffer);
+                self.state_transfer();
+            }
+        } else if self.ticks() - gap_since >= self.config.prepare_gap_timeout {
+            let last_op_number = *buffer.prepares.keys().next_back().unwrap();
+            let op_numbers = (self.op_number() + 1..last_op_number)
+                .filter(|op_number| !buffer.prepares.contains_key(op_number))
+                .collect();
+            buffer.repair_requested = Some(self.ticks());
+            drop(buffer);
+            self.send_msg_to_primary(Message::GetPrepares {
+                cluster_id: self.config.cluster_id,
+                replica_id: self.self_id,
+                view_number: self.view_number,
+                op_numbers,
+            });
+        }
+    }
+
     /// Backup nodes send `PrepareOk` message to the primary to acknowledge that
-    /// they have appended an op to their logs. When the primary has
-    /// received `PrepareOk` messages from a quorum of replicas, it commits
-    /// the operation and replies to the client.
-    fn on_prepare_ok(&self, view_number: ViewNumber, op_number: OpNumber) {
+    /// they have appended every op up to `op_number` to their logs. When the
+    /// primary has received `PrepareOk` messages from a quorum of replicas,
+    /// it commits the operations and replies to the clients.
+    fn on_prepare_ok(&self, view_number: ViewNumber, op_number: OpNumber, replica_id: ReplicaID) {
         assert!(self.is_primary());
         assert_eq!(self.view_number, view_number);
-        // Register the acknowledgement
-        let mut acks = self.acks.borrow_mut();
-        let acks = acks.get_mut(&op_number).unwrap();
-        *acks += 1;
-        // If we have received a quorum of `PrepareOk` messages, commit the
-        // operation and reply to the client.
-        if *acks == self.config.quorum() {
-            let response = self.commit_op(op_number - 1);
-            self.respond_to_client(response);
+        // Register the acknowledgement. Acknowledgements are cumulative, so
+        // a duplicate or reordered `PrepareOk` cannot move us backwards.
+        {
+            let mut acks = self.acks.borrow_mut();
+            let acked = acks.entry(replica_id).or_default();
+            *acked = (*acked).max(op_number);
+        }
+        self.commit_acked_ops();
+        // Committing ops makes room in the pipeline for queued requests and
+        // can complete pending queries.
+        self.prepare_batches();
+        self.execute_queries();
+    }
+
+    /// Commits every op up to the highest op number that a quorum of
+    /// replicas has acknowledged, in log order, and replies to the client
+    /// of each op.
+    fn commit_acked_ops(&self) {
+        let quorum_op_number = {
+            let acks = self.acks.borrow();
+            let mut acked: Vec<OpNumber> = self
+                .config
+                .voter_ids()
+                .into_iter()
+                .filter(|replica_id| *replica_id != self.self_id)
+                .map(|replica_id| acks.get(&replica_id).copied().unwrap_or(0))
+                .collect();
+            // We have every op in our log, so we acknowledge all of them.
+            acked.push(self.op_number());
+            acked.sort_unstable_by(|a, b| b.cmp(a));
+            acked[self.config.quorum() - 1].min(self.op_number())
+        };
+        for op_number in self.committed() + 1..=quorum_op_number {
+            if !self.commit_op(op_number - 1, true) {
+                break;
+            }
+        }
+    }
+
+    /// The client sends a `Query` message to execute a read-only query.
+    ///
+    /// To answer a linearizable query without appending it to the log, the
+    /// primary first confirms with a quorum of replicas that it is still the
+    /// primary, and waits until it has committed every op that was in its
+    /// log when the query arrived. While the primary holds a leader lease, it
+    /// answers the query from its committed state instead. A backup replies
+    /// to a linearizable query with `ReplyError::NotPrimary`.
+    ///
+    /// Any replica answers a bounded-staleness query once its commit number
+    /// satisfies the bound. Queries that wait too long time out, and a
+    /// replica with too many waiting queries replies with `ReplyError::Busy`.
+    fn on_query(
+        &self,
+        client_id: ClientID,
+        request_number: RequestNumber,
+        query: SM::Query,
+        consistency: ReadConsistency,
+    ) {
+        let queued = {
+            let reads = self.reads.borrow();
+            reads.pending.len() + reads.stale.len()
+        };
+        if queued >= self.config.max_queued_queries {
+            self.respond_with_error(client_id, request_number, ReplyError::Busy);
+            return;
+        }
+        let arrived = self.ticks();
+        if consistency != ReadConsistency::Linearizable {
+            self.reads.borrow_mut().stale.push(StaleQuery {
+                client_id,
+                request_number,
+                query,
+                consistency,
+                arrived,
+            });
+            self.execute_queries();
+            return;
+        }
+        if !self.is_primary() {
+            self.respond_with_error(client_id, request_number, ReplyError::NotPrimary);
+            return;
+        }
+        if *self.status.borrow() != Status::Normal {
+            self.respond_with_error(client_id, request_number, ReplyError::Busy);
+            return;
+        }
+        if self.has_lease() {
+            let mut reads = self.reads.borrow_mut();
+            let round = reads.confirmed_round;
+            reads.pending.push_back(PendingQuery {
+                client_id,
+                request_number,
+                query,
+                op_number: self.committed(),
+                round,
+                arrived,
+            });
+            drop(reads);
+            self.execute_queries();
+            return;
+        }
+        let round = self.start_heartbeat_round();
+        self.reads.borrow_mut().pending.push_back(PendingQuery {
+            client_id,
+            request_number,
+            query,
+            op_number: self.op_number(),
+            round,
+            arrived,
+        });
+        // If we are the only replica, our own acknowledgement is already a
+        // quorum.
+        self.confirm_round(round);
+    }
+
+    /// Starts a new heartbeat round and returns its number.
+    fn start_heartbeat_round(&self) -> u64 {
+        let round = {
+            let mut reads = self.reads.borrow_mut();
+            // A round that started a lease duration ago can no longer grant
+            // a lease, and the queries waiting on it have timed out or are
+            // answered by any later round, so we drop it rather than keep
+            // every round that never reached a quorum.
+            let window = self
+                .config
+                .lease_duration
+                .unwrap_or(0)
+                .max(self.config.query_timeout);
+            let now = self.ticks();
+            reads
+                .rounds
+                .retain(|_, heartbeat_round| heartbeat_round.started + window > now);
+            reads.round += 1;
+            let round = reads.round;
+            reads.rounds.insert(
+                round,
+                HeartbeatRound {
+                    started: self.ticks(),
+                    acks: HashSet::from([self.self_id]),
+                },
+            );
+            round
+        };
+        self.send_msg_to_others(Message::Heartbeat {
+            cluster_id: self.config.cluster_id,
+            view_number: self.view_number,
+            round,
+        });
+        round
+    }
+
+    /// Returns true if we hold a leader lease.
+    fn has_lease(&self) -> bool {
+        self.config.lease_duration.is_some() && self.ticks() < self.reads.borrow().lease_expiry
+    }
+
+    /// The primary sends a `Heartbeat` message to the backups, which
+    /// acknowledge that they still consider it the primary. If leases are
+    /// enabled, the acknowledgement is also a promise not to start a view
+    /// change for the lease duration.
+    fn on_heartbeat(&self, view_number: ViewNumber, round: u64) {
+        if *self.status.borrow() != Status::Normal || view_number != self.view_number {
+            return;
+        }
+        self.primary_contact.store(self.ticks(), Ordering::SeqCst);
+        // Learners do not count towards the quorum, so they neither confirm
+        // the primary nor promise it a lease.
+        if !self.config.is_voter(self.self_id) {
+            return;
+        }
+        if let Some(lease_duration) = self.config.lease_duration {
+            self.lease_promise
+                .fetch_max(self.ticks() + lease_duration, Ordering::SeqCst);
+        }
+        self.send_msg_to_primary(Message::HeartbeatOk {
+            cluster_id: self.config.cluster_id,
+            view_number,
+            round,
+            replica_id: self.self_id,
+        });
+    }
+
+    /// The primary receives a `HeartbeatOk` message from a backup that
+    /// acknowledges heartbeat round `round`.
+    fn on_heartbeat_ok(&self, view_number: ViewNumber, round: u64, replica_id: ReplicaID) {
+        if !self.is_primary() || view_number != self.view_number {
+            return;
+        }
+        // Learners do not count towards the quorum.
+        if !self.config.is_voter(replica_id) {
+            return;
+        }
+        if let Some(heartbeat_round) = self.reads.borrow_mut().rounds.get_mut(&round) {
+            heartbeat_round.acks.insert(replica_id);
+        }
+        self.confirm_round(round);
+    }
+
+    /// If a quorum has acknowledged heartbeat round `round`, every query that
+    /// arrived before the round started can be answered. If leases are
+    /// enabled, the quorum also grants us a lease that lasts from the start
+    /// of the round, minus a safety margin for clock drift.
+    fn confirm_round(&self, round: u64) {
+        {
+            let mut reads = self.reads.borrow_mut();
+            let Some(started) = reads
+                .rounds
+                .get(&round)
+                .filter(|heartbeat_round| heartbeat_round.acks.len() >= self.config.quorum())
+                .map(|heartbeat_round| heartbeat_round.started)
+            else {
+                return;
+            };
+            reads.confirmed_round = reads.confirmed_round.max(round);
+            reads.rounds = reads.rounds.split_off(&(round + 1));
+            if let Some(lease_duration) = self.config.lease_duration {
+                let lease_expiry =
+                    (started + lease_duration).saturating_sub(self.config.lease_clock_drift);
+                reads.lease_expiry = reads.lease_expiry.max(lease_expiry);
+            }
+        }
+        self.execute_queries();
+    }
+
+    /// Executes pending queries whose heartbeat round is confirmed and whose
+    /// op number is committed, and bounded-staleness queries whose bound is
+    /// satisfied, and replies to the clients. A query runs after every op
+    /// committed before it has been applied, as the apply executor processes
+    /// its tasks in order.
+    fn execute_queries(&self) {
+        let stale = {
+            let mut reads = self.reads.borrow_mut();
+            let (ready, waiting) = std::mem::take(&mut reads.stale)
+                .into_iter()
+                .partition(|query| self.satisfies(query.consistency));
+            reads.stale = waiting;
+            ready
+        };
+        for query in stale {
+            self.execute_query(query.client_id, query.request_number, query.query);
+        }
+        loop {
+            let query = {
+                let mut reads = self.reads.borrow_mut();
+                let ready = reads.pending.front().is_some_and(|query| {
+                    query.round <= reads.confirmed_round && query.op_number <= self.committed()
+                });
+                if !ready {
+                    break;
+                }
+                reads.pending.pop_front().unwrap()
+            };
+            self.execute_query(query.client_id, query.request_number, query.query);
+        }
+    }
+
+    /// Returns true if our state satisfies the bound of a bounded-staleness
+    /// query.
+    fn satisfies(&self, consistency: ReadConsistency) -> bool {
+        match consistency {
+            ReadConsistency::Linearizable => false,
+            ReadConsistency::MinCommit(commit_number) => self.committed() >= commit_number,
+            ReadConsistency::MaxLag(max_lag) => {
+                if self.is_primary() {
+                    return true;
+                }
+                // A backup that has not heard from the primary for a while,
+                // for example because it is partitioned, does not know how
+                // far it lags behind.
+                let silence = self.ticks() - self.primary_contact.load(Ordering::SeqCst);
+                let primary_commit_number = self.primary_commit_number.load(Ordering::SeqCst);
+                silence <= self.config.stale_read_timeout
+                    && self.committed() + max_lag >= primary_commit_number
+            }
+        }
+    }
+
+    /// Replies with `ReplyError::Timeout` to the queries that have waited
+    /// longer than the query timeout.
+    fn expire_queries(&self) {
+        let now = self.ticks();
+        let timeout = self.config.query_timeout;
+        let mut expired = Vec::new();
+        {
+            let mut reads = self.reads.borrow_mut();
+            reads.pending.retain(|query| {
+                let waiting = now - query.arrived < timeout;
+                if !waiting {
+                    expired.push((query.client_id, query.request_number));
+                }
+                waiting
+            });
+            reads.stale.retain(|query| {
+                let waiting = now - query.arrived < timeout;
+                if !waiting {
+                    expired.push((query.client_id, query.request_number));
+                }
+                waiting
+            });
+        }
+        for (client_id, request_number) in expired {
+            self.respond_with_error(client_id, request_number, ReplyError::Timeout);
+        }
+    }
+
+    fn respond_with_error(
+        &self,
+        client_id: ClientID,
+        request_number: RequestNumber,
+        error: ReplyError,
+    ) {
+        self.respond_to_client(Reply {
+            view_number: self.view_number,
+            client_id,
+            request_number,
+            result: Err(error),
+        });
+    }
+
+    fn execute_query(&self, client_id: ClientID, request_number: RequestNumber, query: SM::Query) {
+        if let Some(apply_tx) = &self.apply_tx {
+            apply_tx
+                .send(ApplyTask::Query {
+                    view_number: self.view_number,
+                    client_id,
+                    request_number,
+                    query,
+                })
+                .unwrap();
+            return;
         }
+        let result = match self.failure() {
+            Some(reason) => Err(ReplyError::Unhealthy(reason)),
+            None => self
+                .state_machine
+                .query(query)
+                .map_err(ReplyError::Rejected),
+        };
+        self.respond_to_client(Reply {
+            view_number: self.view_number,
+            client_id,
+            request_number,
+            result,
+        });
     }
 
     /// A backup node typically commits its log as part of `Prepare`
@@ -210,13 +1032,24 @@ impl<SM: StateMachine> Replica<SM> {
         }
         assert_eq!(*self.status.borrow(), Status::Normal);
         assert_eq!(self.view_number, view_number);
+        self.primary_commit_number
+            .fetch_max(commit_number, Ordering::SeqCst);
+        self.primary_contact.store(self.ticks(), Ordering::SeqCst);
         if commit_number > self.op_number() {
-            self.state_transfer();
+            // If we are waiting for a gap in our log to fill, the missing ops
+            // are on their way, so there is no need for state transfer.
+            if self.prepare_buffer.borrow().prepares.is_empty() {
+                self.state_transfer();
+            }
             return;
         }
-        for op_idx in self.commit_number()..commit_number {
-            self.commit_op(op_idx);
+        self.commit_ops(commit_number);
+        // If the primary has not committed every op we have, our last
+        // `PrepareOk` message may have been lost.
+        if commit_number < self.op_number() {
+            self.send_prepare_ok();
         }
+        self.execute_queries();
     }
 
     /// A replica sends a `GetState` message to another replica to catch
@@ -228,21 +1061,51 @@ impl<SM: StateMachine> Replica<SM> {
         self.send_msg(
             replica_id,
             Message::NewState {
+                cluster_id: self.config.cluster_id,
                 view_number: self.view_number,
                 log: log[op_number..].to_vec(),
                 op_number_start: op_number,
                 op_number_end: self.op_number(),
-                commit_number: self.commit_number(),
+                commit_number: self.committed(),
             },
         );
     }
 
+    /// The primary receives a `GetPrepares` message from a backup that has a
+    /// gap in its log, and resends the missing ops as `Prepare` messages.
+    fn on_get_prepares(
+        &self,
+        replica_id: ReplicaID,
+        view_number: ViewNumber,
+        op_numbers: Vec<OpNumber>,
+    ) {
+        if *self.status.borrow() != Status::Normal || view_number != self.view_number {
+            return;
+        }
+        let log = self.log.borrow();
+        for op_number in op_numbers {
+            if op_number == 0 || op_number > self.op_number() {
+                continue;
+            }
+            self.send_msg(
+                replica_id,
+                Message::Prepare {
+                    cluster_id: self.config.cluster_id,
+                    view_number: self.view_number,
+                    op_number,
+                    entries: vec![log[op_number - 1].clone()],
+                    commit_number: self.committed(),
+                },
+            );
+        }
+    }
+
     /// A replica receives a `NewState` message in response to a
     /// `GetState` message it sent itself to catch up on its log.
     fn on_new_state(
         &self,
         view_number: ViewNumber,
-        log: Vec<SM::Input>,
+        log: Vec<LogEntry<SM::Input>>,
         op_number_start: OpNumber,
         op_number_end: OpNumber,
         commit_number: CommitID,
@@ -250,54 +1113,108 @@ impl<SM: StateMachine> Replica<SM> {
         if *self.status.borrow() != Status::Recovery {
             return;
         }
-        assert_eq!(*self.status.borrow(), Status::Recovery);
         assert_eq!(self.view_number, view_number);
-        assert_eq!(op_number_start, self.op_number());
-        for op in log {
-            self.append_to_log(op);
+        // A delayed reply to an earlier `GetState` message does not start
+        // where our log ends.
+        if op_number_start != self.op_number() {
+            return;
         }
-        for op_idx in self.commit_number()..commit_number {
-            self.commit_op(op_idx);
+        for entry in log {
+            self.append_to_log(entry);
         }
+        self.commit_ops(commit_number);
         assert_eq!(self.op_number(), op_number_end);
-        assert_eq!(self.commit_number(), commit_number);
+        // We may already have committed more ops than the reply reports, if
+        // a `Prepare` or `Commit` message overtook it.
+        assert!(!self.is_healthy() || self.committed() >= commit_number);
         self.status.replace(Status::Normal);
-        let view_number = self.view_number;
-        self.send_msg_to_primary(Message::PrepareOk {
-            view_number,
-            op_number: op_number_end,
-        });
+        // Append the `Prepare` messages we buffered during state transfer.
+        self.append_buffered_prepares();
+        self.send_prepare_ok();
+        self.execute_queries();
     }
 
-    /// When there are no client requests, the primary node sends a
-    /// `Commit` message to backup nodes periodically to let them commit
-    /// if needed.
+    /// The `on_idle` function is called periodically and serves as the
+    /// replica's tick source. When there are no client requests, the primary
+    /// node sends a `Commit` message to backup nodes to let them commit if
+    /// needed. The primary also replicates a batch of requests that has waited
+    /// long enough. Backup nodes use the tick to repair gaps in their logs.
     pub fn on_idle(&self) {
+        self.ticks.fetch_add(1, Ordering::SeqCst);
+        self.expire_queries();
         if !self.is_primary() {
+            self.repair_prepare_gap();
             return;
         }
         assert_eq!(*self.status.borrow(), Status::Normal);
+        self.resend_prepares();
+        self.prepare_batches();
+        // Renew our leader lease.
+        if self.config.lease_duration.is_some() {
+            let round = self.start_heartbeat_round();
+            self.confirm_round(round);
+        }
         let view_number = self.view_number;
-        let commit_number = self.commit_number();
+        let commit_number = self.committed();
         self.send_msg_to_others(Message::Commit {
+            cluster_id: self.config.cluster_id,
             view_number,
             commit_number,
         });
     }
 
-    fn append_to_log(&self, op: SM::Input) {
+    /// Resends the uncommitted ops to every replica that has not
+    /// acknowledged them, in case the `Prepare` or `PrepareOk` messages were
+    /// lost. Replicas that miss committed ops catch up from `Commit`
+    /// messages instead.
+    fn resend_prepares(&self) {
+        let op_number = self.op_number();
+        let commit_number = self.committed();
+        if op_number == commit_number {
+            return;
+        }
+        let acks = self.acks.borrow();
+        let log = self.log.borrow();
+        // Learners do not acknowledge ops, so they repair gaps in their logs
+        // themselves.
+        for replica_id in self.config.voter_ids() {
+            if replica_id == self.self_id {
+                continue;
+            }
+            let acked = acks.get(&replica_id).copied().unwrap_or(0);
+            if acked >= op_number {
+                continue;
+            }
+            let first = acked.max(commit_number);
+            self.send_msg(
+                replica_id,
+                Message::Prepare {
+                    cluster_id: self.config.cluster_id,
+                    view_number: self.view_number,
+                    op_number,
+                    entries: log[first..op_number].to_vec(),
+                    commit_number,
+                },
+            );
+        }
+    }
+
+    fn append_to_log(&self, entry: LogEntry<SM::Input>) {
         let mut log = self.log.borrow_mut();
-        log.push(op);
+        log.push(entry);
         self.op_number.fetch_add(1, Ordering::SeqCst);
     }
 
     fn state_transfer(&self) {
         self.status.replace(Status::Recovery);
+        self.state_transfer_tick
+            .store(self.ticks(), Ordering::SeqCst);
         // FIXME: pick *one* replica, doesn't need to be primary.
         let primary_id = self.primary_id();
         self.send_msg(
             primary_id,
             Message::GetState {
+                cluster_id: self.config.cluster_id,
                 replica_id: self.self_id,
                 view_number: self.view_number,
                 op_number: self.op_number(),
@@ -305,41 +1222,108 @@ impl<SM: StateMachine> Replica<SM> {
         );
     }
 
-    /// Commits an operation at log index `op_idx`.
-    fn commit_op(&self, op_idx: usize) -> SM::Output {
-        let log = self.log.borrow();
-        let op = &log[op_idx];
-        let ret = self.state_machine.apply(op.clone());
-        self.commit_number.fetch_add(1, Ordering::SeqCst);
-        ret
+    /// Returns the context of the next op in our log. The timestamp comes
+    /// from our clock, but never goes backwards in log order, even if the
+    /// clock does or the previous primary's clock was ahead.
+    fn next_op_context(&self) -> OpContext {
+        let now = self.clock.now();
+        let last_timestamp = self
+            .log
+            .borrow()
+            .last()
+            .map_or(0, |entry| entry.context.timestamp);
+        let timestamp = now.max(last_timestamp);
+        // The seed must not depend on the Rust release the replica was
+        // built with, so we mix the inputs with SplitMix64 rather than with
+        // the standard library's hasher.
+        let mut seed = splitmix64(self.seed_key ^ self.view_number as u64);
+        seed = splitmix64(seed ^ (self.op_number() + 1) as u64);
+        seed = splitmix64(seed ^ timestamp);
+        OpContext { timestamp, seed }
+    }
+
+    /// Commits every op up to `commit_number`, unless applying an op fails.
+    fn commit_ops(&self, commit_number: CommitID) {
+        for op_idx in self.committed()..commit_number {
+            if !self.commit_op(op_idx, false) {
+                break;
+            }
+        }
+    }
+
+    /// Commits op `op_idx` by applying it to the state machine, and replies
+    /// to the client if `respond` is set. If applying fails
+    /// non-deterministically, the replica becomes unhealthy, the commit
+    /// number does not move, and we return false.
+    ///
+    /// With an apply executor, the op is queued for the executor, which
+    /// applies it and replies to the client later.
+    fn commit_op(&self, op_idx: usize, respond: bool) -> bool {
+        if !self.is_healthy() {
+            return false;
+        }
+        let entry = self.log.borrow()[op_idx].clone();
+        if let Some(apply_tx) = &self.apply_tx {
+            apply_tx
+                .send(ApplyTask::Apply {
+                    view_number: self.view_number,
+                    op_number: op_idx + 1,
+                    entry,
+                    respond,
+                })
+                .unwrap();
+            // The executor advances the applied number once it applies the op.
+            self.commit_number.fetch_add(1, Ordering::SeqCst);
+            return true;
+        }
+        let (client_id, request_number) = (entry.client_id, entry.request_number);
+        match apply_op(&*self.state_machine, entry) {
+            Ok(result) => {
+                self.commit_number.fetch_add(1, Ordering::SeqCst);
+                self.applied_number.fetch_add(1, Ordering::SeqCst);
+                if respond {
+                    self.respond_to_client(Reply {
+                        view_number: self.view_number,
+                        client_id,
+                        request_number,
+                        result,
+                    });
+                }
+                true
+            }
+            Err(reason) => {
+                fail(&self.failure, self.self_id, op_idx + 1, reason);
+                false
+            }
+        }
     }
 
     /// Sends a message to the primary.
-    fn send_msg_to_primary(&self, message: Message<SM::Input>) {
+    fn send_msg_to_primary(&self, message: SmMessage<SM>) {
         let primary_id = self.primary_id();
         self.send_msg(primary_id, message);
     }
 
     /// Sends a message to all other replicas.
-    fn send_msg_to_others(&self, message: Message<SM::Input>) {
-        let replicas = self.config.replicas.borrow();
-        for replica_id in replicas.iter() {
-            if *replica_id == self.self_id {
+    fn send_msg_to_others(&self, message: SmMessage<SM>) {
+        for replica_id in self.config.replica_ids() {
+            if replica_id == self.self_id {
                 continue;
             }
-            self.send_msg(*replica_id, message.clone());
+            self.send_msg(replica_id, message.clone());
         }
     }
 
-    fn send_msg(&self, replica_id: ReplicaID, message: Message<SM::Input>) {
+    fn send_msg(&self, replica_id: ReplicaID, message: SmMessage<SM>) {
         self.replica_tx.send((replica_id, message)).unwrap();
     }
 
-    fn respond_to_client(&self, response: SM::Output) {
-        self.client_tx.send(response).unwrap();
+    fn respond_to_client(&self, reply: Reply<SM::Output>) {
+        self.client_tx.send(reply).unwrap();
     }
 
-    fn is_primary(&self) -> bool {
+    /// Returns true if this replica is the primary of its view.
+    pub fn is_primary(&self) -> bool {
         self.self_id == self.primary_id()
     }
 
@@ -347,11 +1331,227 @@ impl<SM: StateMachine> Replica<SM> {
         self.config.primary_id(self.view_number)
     }
 
-    fn commit_number(&self) -> CommitID {
+    /// Returns the ID of this replica.
+    pub fn replica_id(&self) -> ReplicaID {
+        self.self_id
+    }
+
+    /// Returns the status of this replica.
+    pub fn status(&self) -> Status {
+        *self.status.borrow()
+    }
+
+    /// Returns the view this replica is in.
+    pub fn view_number(&self) -> ViewNumber {
+        self.view_number
+    }
+
+    /// Returns the number of ops this replica has committed and applied to
+    /// its state machine. Ops that wait for the apply executor, or that come
+    /// after an op that failed to apply, do not count.
+    pub fn commit_number(&self) -> CommitID {
+        self.applied_number.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of committed ops, including those the apply
+    /// executor has not applied yet.
+    fn committed(&self) -> CommitID {
         self.commit_number.load(Ordering::SeqCst)
     }
 
-    fn op_number(&self) -> OpNumber {
+    /// Returns the number of ops in the log of this replica.
+    pub fn op_number(&self) -> OpNumber {
         self.op_number.load(Ordering::SeqCst)
     }
+
+    /// Returns the log of this replica. The first `commit_number()` entries
+    /// are committed.
+    pub fn log(&self) -> Ref<'_, [LogEntry<SM::Input>]> {
+        Ref::map(self.log.borrow(), |log| log.as_slice())
+    }
+
+    fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::SeqCst)
+    }
+
+    /// Recovers a replica that restarted without its state by transferring
+    /// the state from the primary. Until the state transfer completes, the
+    /// replica does not acknowledge any ops.
+    pub fn recover(&self) {
+        assert!(!self.is_primary());
+        self.state_transfer();
+    }
+
+    /// Replaces the clock from which the primary takes op timestamps.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Replaces the random key from which the primary derives op seeds, which
+    /// makes the seeds reproducible.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed_key = seed;
+    }
+
+    /// Returns true unless applying an op failed non-deterministically.
+    pub fn is_healthy(&self) -> bool {
+        self.failure.lock().is_none()
+    }
+
+    /// Returns why applying an op failed, if it did.
+    pub fn failure(&self) -> Option<String> {
+        self.failure.lock().clone()
+    }
+
+    /// Returns the tick until which this replica promised the primary not to
+    /// start a view change. View changes must not start before this tick.
+    pub fn lease_promise(&self) -> u64 {
+        self.lease_promise.load(Ordering::SeqCst)
+    }
+}
+
+impl<SM> Replica<SM>
+where
+    SM: StateMachine + Send + Sync + 'static,
+    SM::Input: 'static,
+    SM::Query: 'static,
+    SM::Output: Send + 'static,
+{
+    /// Creates a replica that applies committed ops on a separate thread, so
+    /// that a slow state machine does not block replication. The executor
+    /// applies ops and executes queries in commit order, and replies to the
+    /// clients when it is done. Messages to other replicas carry the number
+    /// of ops handed to the executor, while `commit_number` counts only the
+    /// ops it has applied.
+    pub fn with_async_apply(
+        self_id: ReplicaID,
+        config: Arc<Config>,
+        state_machine: Arc<SM>,
+        client_tx: Sender<Reply<SM::Output>>,
+        replica_tx: Sender<(ReplicaID, SmMessage<SM>)>,
+    ) -> Replica<SM> {
+        let mut replica = Replica::new(
+            self_id,
+            config,
+            state_machine.clone(),
+            client_tx.clone(),
+            replica_tx,
+        );
+        let (apply_tx, apply_rx) = crossbeam_channel::unbounded();
+        let applied_number = replica.applied_number.clone();
+        let failure = replica.failure.clone();
+        std::thread::spawn(move || {
+            run_apply_executor(
+                self_id,
+                &*state_machine,
+                apply_rx,
+                client_tx,
+                applied_number,
+                failure,
+            )
+        });
+        replica.apply_tx = Some(apply_tx);
+        replica
+    }
+}
+
+/// Processes apply tasks in order until the replica is dropped, and advances
+/// `applied_number` as it applies ops. After an op fails
+/// non-deterministically, the executor applies no more ops and answers no
+/// more queries.
+fn run_apply_executor<SM: StateMachine>(
+    self_id: ReplicaID,
+    state_machine: &SM,
+    apply_rx: Receiver<ApplyTask<SM::Input, SM::Query>>,
+    client_tx: Sender<Reply<SM::Output>>,
+    applied_number: Arc<AtomicUsize>,
+    failure: Arc<Mutex<Option<String>>>,
+) {
+    for task in apply_rx {
+        match task {
+            ApplyTask::Apply {
+                view_number,
+                op_number,
+                entry,
+                respond,
+            } => {
+                if failure.lock().is_some() {
+                    continue;
+                }
+                let (client_id, request_number) = (entry.client_id, entry.request_number);
+                match apply_op(state_machine, entry) {
+                    Ok(result) => {
+                        applied_number.store(op_number, Ordering::SeqCst);
+                        if respond {
+                            let reply = Reply {
+                                view_number,
+                                client_id,
+                                request_number,
+                                result,
+                            };
+                            let _ = client_tx.send(reply);
+                        }
+                    }
+                    Err(reason) => fail(&failure, self_id, op_number, reason),
+                }
+            }
+            ApplyTask::Query {
+                view_number,
+                client_id,
+                request_number,
+                query,
+            } => {
+                let result = match failure.lock().clone() {
+                    Some(reason) => Err(ReplyError::Unhealthy(reason)),
+                    None => state_machine.query(query).map_err(ReplyError::Rejected),
+                };
+                let reply = Reply {
+                    view_number,
+                    client_id,
+                    request_number,
+                    result,
+                };
+                let _ = client_tx.send(reply);
+            }
+        }
+    }
+}
+
+/// The SplitMix64 finalizer, which scrambles `x` into a well-distributed
+/// 64-bit value.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Applies a log entry to the state machine. Returns the result for the
+/// client, or the reason if applying failed non-deterministically.
+fn apply_op<SM: StateMachine>(
+    state_machine: &SM,
+    entry: LogEntry<SM::Input>,
+) -> Result<Result<SM::Output, ReplyError>, String> {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        state_machine.try_apply(entry.op, &entry.context)
+    }));
+    match result {
+        Ok(Ok(output)) => Ok(Ok(output)),
+        Ok(Err(ApplyError::Deterministic(reason))) => Ok(Err(ReplyError::Failed(reason))),
+        Ok(Err(ApplyError::Fatal(reason))) => Err(reason),
+        Err(payload) => Err(payload
+            .downcast_ref::<&str>()
+            .map(|reason| reason.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "panic".to_string())),
+    }
+}
+
+/// Marks a replica unhealthy because applying op `op_number` failed.
+fn fail(failure: &Mutex<Option<String>>, self_id: ReplicaID, op_number: OpNumber, reason: String) {
+    error!(
+        "Replica {} failed to apply op {}: {}",
+        self_id, op_number, reason
+    );
+    *failure.lock() = Some(reason);
 }
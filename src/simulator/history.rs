@@ -0,0 +1,72 @@
+//! History of client operations.
+//!
+//! The history records when each client invokes an op and when the op
+//! returns, in simulated time. Each client has at most one op in flight, so
+//! a return belongs to the last op the client invoked.
+
+use crate::message::ReplyError;
+use crate::types::ClientID;
+
+/// An op in the history.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Operation<Op, Output> {
+    pub client_id: ClientID,
+    pub op: Op,
+    /// The time at which the client invoked the op.
+    pub call: u64,
+    /// The time at which the op returned and its result, or `None` if it is
+    /// still pending. A pending op may or may not have taken effect.
+    pub ret: Option<(u64, Result<Output, ReplyError>)>,
+}
+
+/// History of the ops of every client.
+#[derive(Clone, Debug)]
+pub struct History<Op, Output> {
+    operations: Vec<Operation<Op, Output>>,
+}
+
+impl<Op, Output> Default for History<Op, Output> {
+    fn default() -> History<Op, Output> {
+        History::new()
+    }
+}
+
+impl<Op, Output> History<Op, Output> {
+    pub fn new() -> History<Op, Output> {
+        History {
+            operations: Vec::new(),
+        }
+    }
+
+    /// Records that client `client_id` invoked `op` at time `time`.
+    pub fn invoke(&mut self, time: u64, client_id: ClientID, op: Op) {
+        assert!(self.pending(client_id).is_none());
+        self.operations.push(Operation {
+            client_id,
+            op,
+            call: time,
+            ret: None,
+        });
+    }
+
+    /// Records that the pending op of client `client_id` returned `result`
+    /// at time `time`.
+    pub fn complete(&mut self, time: u64, client_id: ClientID, result: Result<Output, ReplyError>) {
+        let idx = self
+            .pending(client_id)
+            .expect("client has no pending operation");
+        self.operations[idx].ret = Some((time, result));
+    }
+
+    /// Returns the ops in the order they were invoked.
+    pub fn operations(&self) -> &[Operation<Op, Output>] {
+        &self.operations
+    }
+
+    fn pending(&self, client_id: ClientID) -> Option<usize> {
+        self.operations
+            .iter()
+            .rposition(|operation| operation.client_id == client_id)
+            .filter(|idx| self.operations[*idx].ret.is_none())
+    }
+}
@@ -0,0 +1,199 @@
+//! Cross-replica safety invariants.
+//!
+//! The simulator checks the invariants after every step, that is, after
+//! every message a replica handles and every call to `Replica::on_idle`. A
+//! violation means that the replicas can diverge, or already have.
+
+use crate::message::{LogEntry, OpContext};
+use crate::replica::{Replica, Status};
+use crate::state_machine::StateMachine;
+use crate::types::{ClientID, CommitID, ReplicaID, RequestNumber, ViewNumber};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A violated safety invariant.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvariantViolation {
+    /// A replica committed an op other than the one the cluster committed
+    /// at the same position of the log.
+    CommittedLogMismatch {
+        replica_id: ReplicaID,
+        op_number: usize,
+    },
+    /// The commit number of a replica went backwards.
+    CommitNumberDecreased {
+        replica_id: ReplicaID,
+        from: CommitID,
+        to: CommitID,
+    },
+    /// More than one replica is a primary in normal status in the same view.
+    MultiplePrimaries {
+        view_number: ViewNumber,
+        replica_ids: Vec<ReplicaID>,
+    },
+    /// The primary of the latest view does not have an op that the cluster
+    /// committed.
+    CommittedOpLost {
+        replica_id: ReplicaID,
+        op_number: usize,
+    },
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvariantViolation::CommittedLogMismatch {
+                replica_id,
+                op_number,
+            } => write!(
+                f,
+                "replica {} committed a different op {} than the cluster",
+                replica_id, op_number
+            ),
+            InvariantViolation::CommitNumberDecreased {
+                replica_id,
+                from,
+                to,
+            } => write!(
+                f,
+                "commit number of replica {} went back from {} to {}",
+                replica_id, from, to
+            ),
+            InvariantViolation::MultiplePrimaries {
+                view_number,
+                replica_ids,
+            } => write!(
+                f,
+                "replicas {:?} are all primaries in view {}",
+                replica_ids, view_number
+            ),
+            InvariantViolation::CommittedOpLost {
+                replica_id,
+                op_number,
+            } => write!(f, "primary {} lost committed op {}", replica_id, op_number),
+        }
+    }
+}
+
+impl std::error::Error for InvariantViolation {}
+
+/// Identity of a log entry. Ops need not be comparable, but the primary
+/// assigns every entry a unique request and context.
+type EntryID = (ClientID, RequestNumber, OpContext);
+
+fn entry_id<Op>(entry: &LogEntry<Op>) -> EntryID {
+    (entry.client_id, entry.request_number, entry.context)
+}
+
+/// Checker of the safety invariants across the replicas of a cluster.
+#[derive(Debug, Default)]
+pub struct Invariants {
+    /// The longest committed log prefix of any replica.
+    committed: Vec<EntryID>,
+    /// The last commit number we saw of each replica.
+    commit_numbers: HashMap<ReplicaID, CommitID>,
+    /// The view and ID of the latest primary, and how many committed ops we
+    /// found in its log.
+    primary_checked: Option<(ViewNumber, ReplicaID, usize)>,
+}
+
+impl Invariants {
+    pub fn new() -> Invariants {
+        Invariants::default()
+    }
+
+    /// Forgets what we saw of replica `replica_id`, which restarted without
+    /// its state.
+    pub fn restart(&mut self, replica_id: ReplicaID) {
+        self.commit_numbers.remove(&replica_id);
+        if self
+            .primary_checked
+            .is_some_and(|(_, primary_id, _)| primary_id == replica_id)
+        {
+            self.primary_checked = None;
+        }
+    }
+
+    /// Checks the invariants across `replicas`, which are the replicas that
+    /// are up.
+    pub fn check<'a, SM: StateMachine + 'a>(
+        &mut self,
+        replicas: impl IntoIterator<Item = &'a Replica<SM>>,
+    ) -> Result<(), InvariantViolation> {
+        let mut primaries: HashMap<ViewNumber, Vec<ReplicaID>> = HashMap::new();
+        let mut latest_primary = None;
+        for replica in replicas {
+            let replica_id = replica.replica_id();
+            let commit_number = replica.commit_number();
+            let last_commit_number = self
+                .commit_numbers
+                .insert(replica_id, commit_number)
+                .unwrap_or(0);
+            if commit_number < last_commit_number {
+                return Err(InvariantViolation::CommitNumberDecreased {
+                    replica_id,
+                    from: last_commit_number,
+                    to: commit_number,
+                });
+            }
+            // Only the ops committed since the last check can disagree, as
+            // the earlier ones were checked already.
+            let log = replica.log();
+            for (op_idx, entry) in log
+                .iter()
+                .enumerate()
+                .take(commit_number)
+                .skip(last_commit_number)
+            {
+                let id = entry_id(entry);
+                match self.committed.get(op_idx) {
+                    Some(committed) if *committed != id => {
+                        return Err(InvariantViolation::CommittedLogMismatch {
+                            replica_id,
+                            op_number: op_idx + 1,
+                        });
+                    }
+                    Some(_) => {}
+                    None => self.committed.push(id),
+                }
+            }
+            if replica.status() == Status::Normal && replica.is_primary() {
+                let view_number = replica.view_number();
+                primaries.entry(view_number).or_default().push(replica_id);
+                if latest_primary.is_none_or(|(view, _)| view < view_number) {
+                    latest_primary = Some((view_number, replica));
+                }
+            }
+        }
+        if let Some((view_number, replica_ids)) =
+            primaries.into_iter().find(|(_, ids)| ids.len() > 1)
+        {
+            return Err(InvariantViolation::MultiplePrimaries {
+                view_number,
+                replica_ids,
+            });
+        }
+        if let Some((view_number, primary)) = latest_primary {
+            let primary_id = primary.replica_id();
+            let checked = match self.primary_checked {
+                Some((view, replica_id, checked))
+                    if view == view_number && replica_id == primary_id =>
+                {
+                    checked
+                }
+                _ => 0,
+            };
+            let log = primary.log();
+            for (op_idx, committed) in self.committed.iter().enumerate().skip(checked) {
+                if log.get(op_idx).map(entry_id) != Some(*committed) {
+                    return Err(InvariantViolation::CommittedOpLost {
+                        replica_id: primary_id,
+                        op_number: op_idx + 1,
+                    });
+                }
+            }
+            self.primary_checked = Some((view_number, primary_id, self.committed.len()));
+        }
+        Ok(())
+    }
+}
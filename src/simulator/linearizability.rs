@@ -0,0 +1,190 @@
+//! Linearizability checker.
+//!
+//! The checker searches for an order of the ops in a history that respects
+//! real time, and in which a sequential model of the state machine produces
+//! the results the clients observed. It follows the algorithm of Wing and
+//! Gong with the state caching of Lowe, as in Knossos and Porcupine.
+
+use crate::message::ReplyError;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::Hash;
+
+use super::history::History;
+
+/// Sequential specification of a state machine.
+pub trait Model {
+    type State: Clone + Eq + Hash;
+    type Op;
+    type Output: PartialEq;
+
+    /// Returns the initial state.
+    fn init(&self) -> Self::State;
+
+    /// Applies `op` to `state`, and returns the new state and the result the
+    /// client should observe.
+    fn step(
+        &self,
+        state: &Self::State,
+        op: &Self::Op,
+    ) -> (Self::State, Result<Self::Output, ReplyError>);
+}
+
+/// A history that is not linearizable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NotLinearizable {
+    /// The largest number of ops the checker could linearize.
+    pub linearized: usize,
+    /// The number of ops that had to be linearized.
+    pub operations: usize,
+}
+
+impl fmt::Display for NotLinearizable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "history is not linearizable: linearized at most {} of {} operations",
+            self.linearized, self.operations
+        )
+    }
+}
+
+impl std::error::Error for NotLinearizable {}
+
+/// An op that the search may linearize.
+struct Entry<'a, Op, Output> {
+    op: &'a Op,
+    call: u64,
+    /// `u64::MAX` for a pending op.
+    ret: u64,
+    /// `None` for a pending op, which may return anything.
+    result: Option<&'a Result<Output, ReplyError>>,
+}
+
+/// A step of the depth-first search: the state after the ops linearized so
+/// far, and the ops that may come next.
+struct Frame<State> {
+    state: State,
+    candidates: Vec<usize>,
+    next: usize,
+}
+
+/// Checks that `history` is linearizable with respect to `model`.
+///
+/// Ops that the primary refused with `ReplyError::Busy`,
+/// `ReplyError::Rejected` or `ReplyError::Unhealthy` never took effect, so
+/// they are left out. Pending
+/// ops may take effect at any point after their invocation, or not at all.
+pub fn check<M: Model>(
+    model: &M,
+    history: &History<M::Op, M::Output>,
+) -> Result<(), NotLinearizable> {
+    let mut entries: Vec<_> = history
+        .operations()
+        .iter()
+        .filter(|operation| {
+            !matches!(
+                operation.ret,
+                Some((
+                    _,
+                    Err(ReplyError::Busy | ReplyError::Rejected(_) | ReplyError::Unhealthy(_))
+                ))
+            )
+        })
+        .map(|operation| Entry {
+            op: &operation.op,
+            call: operation.call,
+            ret: operation.ret.as_ref().map_or(u64::MAX, |(ret, _)| *ret),
+            result: operation.ret.as_ref().map(|(_, result)| result),
+        })
+        .collect();
+    entries.sort_by_key(|entry| entry.call);
+    let operations = entries
+        .iter()
+        .filter(|entry| entry.result.is_some())
+        .count();
+    let mut linearized = vec![false; entries.len()];
+    let mut remaining = operations;
+    let mut most_linearized = 0;
+    let mut path: Vec<usize> = Vec::new();
+    let mut cache = HashSet::new();
+    let mut stack = vec![Frame {
+        state: model.init(),
+        candidates: candidates(&entries, &linearized),
+        next: 0,
+    }];
+    if remaining == 0 {
+        return Ok(());
+    }
+    while let Some(frame) = stack.last_mut() {
+        let Some(&idx) = frame.candidates.get(frame.next) else {
+            // Every candidate is a dead end, so backtrack.
+            stack.pop();
+            if let Some(idx) = path.pop() {
+                linearized[idx] = false;
+                if entries[idx].result.is_some() {
+                    remaining += 1;
+                }
+            }
+            continue;
+        };
+        frame.next += 1;
+        let entry = &entries[idx];
+        let (state, result) = model.step(&frame.state, entry.op);
+        if entry.result.is_some_and(|observed| *observed != result) {
+            continue;
+        }
+        linearized[idx] = true;
+        if !cache.insert((pack(&linearized), state.clone())) {
+            // We have been here before through another order of the same ops.
+            linearized[idx] = false;
+            continue;
+        }
+        path.push(idx);
+        if entry.result.is_some() {
+            remaining -= 1;
+            most_linearized = most_linearized.max(operations - remaining);
+            if remaining == 0 {
+                return Ok(());
+            }
+        }
+        stack.push(Frame {
+            state,
+            candidates: candidates(&entries, &linearized),
+            next: 0,
+        });
+    }
+    Err(NotLinearizable {
+        linearized: most_linearized,
+        operations,
+    })
+}
+
+/// Returns the ops that may be linearized next: the ops that were invoked
+/// before every op left to linearize returned.
+fn candidates<Op, Output>(entries: &[Entry<'_, Op, Output>], linearized: &[bool]) -> Vec<usize> {
+    let min_ret = entries
+        .iter()
+        .zip(linearized)
+        .filter(|(_, linearized)| !**linearized)
+        .map(|(entry, _)| entry.ret)
+        .min()
+        .unwrap_or(u64::MAX);
+    entries
+        .iter()
+        .enumerate()
+        .take_while(|(_, entry)| entry.call <= min_ret)
+        .filter(|(idx, _)| !linearized[*idx])
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+fn pack(bits: &[bool]) -> Vec<u64> {
+    let mut words = vec![0u64; bits.len().div_ceil(64)];
+    for (idx, bit) in bits.iter().enumerate() {
+        if *bit {
+            words[idx / 64] |= 1 << (idx % 64);
+        }
+    }
+    words
+}
@@ -0,0 +1,56 @@
+//! Report of a cluster that failed to make progress.
+
+use crate::replica::Status;
+use crate::types::{ClientID, CommitID, OpNumber, ReplicaID, RequestNumber, ViewNumber};
+use std::fmt;
+
+/// State of a replica when the cluster got stuck.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplicaState {
+    pub replica_id: ReplicaID,
+    /// False if the replica crashed and has not restarted.
+    pub up: bool,
+    pub status: Status,
+    pub view_number: ViewNumber,
+    pub op_number: OpNumber,
+    pub commit_number: CommitID,
+}
+
+/// A cluster that did not complete every request, or whose replicas did not
+/// reach the same commit number, in time after all faults healed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stuck {
+    /// The time at which the check gave up.
+    pub now: u64,
+    /// The requests that did not complete.
+    pub pending_requests: Vec<(ClientID, RequestNumber)>,
+    pub replicas: Vec<ReplicaState>,
+}
+
+impl fmt::Display for Stuck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "cluster is stuck at tick {}", self.now)?;
+        for (client_id, request_number) in &self.pending_requests {
+            writeln!(
+                f,
+                "  client {} waits for request {}",
+                client_id, request_number
+            )?;
+        }
+        for replica in &self.replicas {
+            writeln!(
+                f,
+                "  replica {}: {}, {:?}, view {}, op {}, commit {}",
+                replica.replica_id,
+                if replica.up { "up" } else { "down" },
+                replica.status,
+                replica.view_number,
+                replica.op_number,
+                replica.commit_number
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Stuck {}
@@ -0,0 +1,818 @@
+//! Deterministic simulation of a cluster.
+//!
+//! The simulator runs a cluster of replicas and a set of clients in a single
+//! thread over a simulated network and clock. Every random choice comes from
+//! one generator seeded with `SimulatorConfig::seed`, so the seed of a run
+//! reproduces it exactly.
+//!
+//! Crashes are limited by what replicas implement. Without view changes, the
+//! cluster cannot survive a crashed primary, so only backups crash. Without
+//! a storage layer, a replica keeps nothing across a crash, so a restarted
+//! backup recovers its whole state from the primary. Both limits are
+//! tracked in the README.
+
+use crate::config::Config;
+use crate::message::{Message, Reply};
+use crate::replica::{Clock, Replica, SmMessage};
+use crate::state_machine::StateMachine;
+use crate::types::{ClientID, ReplicaID, RequestNumber};
+use crossbeam_channel::{Receiver, Sender};
+use log::{debug, warn};
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub mod history;
+pub mod invariants;
+pub mod linearizability;
+pub mod liveness;
+pub mod network;
+pub mod shrink;
+pub mod trace;
+
+pub use history::{History, Operation};
+pub use invariants::{InvariantViolation, Invariants};
+pub use linearizability::{Model, NotLinearizable};
+pub use liveness::{ReplicaState, Stuck};
+use network::{Envelope, Network};
+pub use network::{LinkConfig, NetworkConfig, Partition, ScheduledPartition};
+pub use shrink::{regression_test, shrink};
+use trace::Replay;
+pub use trace::{MessageID, Trace, TraceError, TraceEvent};
+
+/// Random number generator of the simulator.
+pub type SimRng = ChaCha8Rng;
+
+/// Default number of replicas in the simulated cluster.
+pub const DEFAULT_REPLICA_COUNT: usize = 3;
+
+/// Default number of simulated clients.
+pub const DEFAULT_CLIENT_COUNT: usize = 3;
+
+/// Default probability that an idle client sends a request in a tick.
+pub const DEFAULT_REQUEST_PROBABILITY: f64 = 0.5;
+
+/// Default number of ticks between calls to `Replica::on_idle`.
+pub const DEFAULT_IDLE_INTERVAL: u64 = 10;
+
+/// Default maximum duration of a random partition in ticks.
+pub const DEFAULT_MAX_PARTITION_DURATION: u64 = 500;
+
+/// Default maximum number of ticks a crashed replica stays down.
+pub const DEFAULT_MAX_DOWNTIME: u64 = 500;
+
+/// Simulator configuration.
+#[derive(Clone, Debug)]
+pub struct SimulatorConfig {
+    /// Seed of the random number generator.
+    pub seed: u64,
+    /// Number of replicas in the cluster.
+    pub replica_count: usize,
+    /// Number of clients. Each client has at most one request in flight.
+    pub client_count: usize,
+    /// Probability that an idle client sends a request in a tick.
+    pub request_probability: f64,
+    /// Number of ticks between calls to `Replica::on_idle`.
+    pub idle_interval: u64,
+    /// Configuration of the simulated network.
+    pub network: NetworkConfig,
+    /// Partitions scheduled ahead of the run.
+    pub partitions: Vec<ScheduledPartition>,
+    /// Probability that a random partition starts in a tick in which no
+    /// partition is active.
+    pub partition_probability: f64,
+    /// Maximum duration of a random partition in ticks.
+    pub max_partition_duration: u64,
+    /// Probability that a random backup crashes in a tick. Only a minority
+    /// of the replicas is down at a time, and the primary never crashes
+    /// because there are no view changes.
+    pub crash_probability: f64,
+    /// Maximum number of ticks a crashed replica stays down.
+    pub max_downtime: u64,
+}
+
+impl SimulatorConfig {
+    pub fn new(seed: u64) -> SimulatorConfig {
+        SimulatorConfig {
+            seed,
+            replica_count: DEFAULT_REPLICA_COUNT,
+            client_count: DEFAULT_CLIENT_COUNT,
+            request_probability: DEFAULT_REQUEST_PROBABILITY,
+            idle_interval: DEFAULT_IDLE_INTERVAL,
+            network: NetworkConfig::new(),
+            partitions: Vec::new(),
+            partition_probability: 0.0,
+            max_partition_duration: DEFAULT_MAX_PARTITION_DURATION,
+            crash_probability: 0.0,
+            max_downtime: DEFAULT_MAX_DOWNTIME,
+        }
+    }
+}
+
+/// Generates the ops that simulated clients send.
+pub trait Workload<Op> {
+    fn gen_op(&mut self, rng: &mut SimRng) -> Op;
+}
+
+impl<Op, F> Workload<Op> for F
+where
+    F: FnMut(&mut SimRng) -> Op,
+{
+    fn gen_op(&mut self, rng: &mut SimRng) -> Op {
+        self(rng)
+    }
+}
+
+/// Simulated clock, which advances one millisecond per tick.
+#[derive(Clone, Debug, Default)]
+struct SimClock {
+    now: Arc<AtomicU64>,
+}
+
+impl SimClock {
+    fn advance(&self) -> u64 {
+        self.now.fetch_add(1, Ordering::SeqCst) + 1
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+/// A replica and the channel of messages it sends.
+struct SimReplica<SM: StateMachine> {
+    replica: Replica<SM>,
+    state_machine: Arc<SM>,
+    outbox: Receiver<(ReplicaID, SmMessage<SM>)>,
+    /// The key from which the replica derives op seeds.
+    seed: u64,
+    /// The tick at which a crashed replica restarts.
+    down_until: Option<u64>,
+}
+
+/// A simulated client.
+#[derive(Debug)]
+struct SimClient {
+    client_id: ClientID,
+    request_number: RequestNumber,
+    /// The request waiting for a reply, if any.
+    in_flight: Option<RequestNumber>,
+}
+
+/// Writes an event to a trace.
+type Recorder<Op> = Box<dyn FnMut(&TraceEvent<Op>)>;
+
+/// Deterministic simulator of a cluster running state machine `SM`.
+pub struct Simulator<SM: StateMachine> {
+    config: SimulatorConfig,
+    rng: SimRng,
+    clock: SimClock,
+    cluster: Arc<Config>,
+    new_state_machine: Box<dyn Fn() -> SM>,
+    workload: Box<dyn Workload<SM::Input>>,
+    replicas: Vec<SimReplica<SM>>,
+    clients: Vec<SimClient>,
+    reply_tx: Sender<Reply<SM::Output>>,
+    reply_rx: Receiver<Reply<SM::Output>>,
+    network: Network<SM::Input, SM::Query>,
+    partitions: Vec<ScheduledPartition>,
+    history: History<SM::Input, SM::Output>,
+    invariants: Invariants,
+    /// Writes the events of the run to a trace, if it is recorded.
+    recorder: Option<Recorder<SM::Input>>,
+    /// The trace that drives the run in place of the random number
+    /// generator, if it is a replay.
+    replay: Option<Replay<SM::Input>>,
+    completed: usize,
+}
+
+impl<SM: StateMachine + 'static> Simulator<SM> {
+    /// Creates a simulator of a cluster whose replicas run state machines
+    /// created by `new_state_machine`, and whose clients send ops generated
+    /// by `workload`.
+    pub fn new(
+        config: SimulatorConfig,
+        new_state_machine: impl Fn() -> SM + 'static,
+        workload: impl Workload<SM::Input> + 'static,
+    ) -> Simulator<SM> {
+        Simulator::with_replay(config, new_state_machine, workload, None)
+    }
+
+    /// Creates a simulator that replays `trace` with replicas that run state
+    /// machines created by `new_state_machine`. Call `replay` to run it.
+    pub fn from_trace(
+        trace: Trace<SM::Input>,
+        new_state_machine: impl Fn() -> SM + 'static,
+    ) -> Simulator<SM> {
+        let mut config = SimulatorConfig::new(0);
+        config.replica_count = trace.replica_count;
+        config.client_count = trace.client_count;
+        config.idle_interval = trace.idle_interval;
+        let workload = |_: &mut SimRng| -> SM::Input { unreachable!("replay has no workload") };
+        let replay = Replay::new(trace);
+        Simulator::with_replay(config, new_state_machine, workload, Some(replay))
+    }
+
+    fn with_replay(
+        config: SimulatorConfig,
+        new_state_machine: impl Fn() -> SM + 'static,
+        workload: impl Workload<SM::Input> + 'static,
+        replay: Option<Replay<SM::Input>>,
+    ) -> Simulator<SM> {
+        let rng = SimRng::seed_from_u64(config.seed);
+        // Replicas share the configuration through an `Arc`, but the
+        // simulator runs them all on one thread.
+        #[allow(clippy::arc_with_non_send_sync)]
+        let cluster = Arc::new(Config::new());
+        for _ in 0..config.replica_count {
+            cluster.add_replica();
+        }
+        let clients = (0..config.client_count)
+            .map(|client_id| SimClient {
+                client_id: client_id as ClientID,
+                request_number: 0,
+                in_flight: None,
+            })
+            .collect();
+        let (reply_tx, reply_rx) = crossbeam_channel::unbounded();
+        let network = Network::new(config.network.clone());
+        let partitions = config.partitions.clone();
+        let mut simulator = Simulator {
+            config,
+            rng,
+            clock: SimClock::default(),
+            cluster,
+            new_state_machine: Box::new(new_state_machine),
+            workload: Box::new(workload),
+            replicas: Vec::new(),
+            clients,
+            reply_tx,
+            reply_rx,
+            network,
+            partitions,
+            history: History::new(),
+            invariants: Invariants::new(),
+            recorder: None,
+            replay,
+            completed: 0,
+        };
+        for replica_id in simulator.cluster.replica_ids() {
+            let replica = simulator.new_replica(replica_id);
+            simulator.replicas.push(replica);
+        }
+        simulator
+    }
+
+    /// Replays the rest of the trace the simulator was created from.
+    pub fn replay(&mut self) {
+        while let Some(event) = self
+            .replay
+            .as_mut()
+            .and_then(|replay| replay.schedule.pop_front())
+        {
+            match event {
+                TraceEvent::Tick(_) => self.tick(true),
+                TraceEvent::Crash { replica_id, until } => self.crash_until(replica_id, until),
+                TraceEvent::Heal => self.heal(),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    /// Runs the simulation for `ticks` ticks.
+    pub fn run(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.tick(true);
+        }
+    }
+
+    /// Runs the simulation for `ticks` ticks without sending new requests
+    /// or injecting new faults, so that requests in flight can complete and
+    /// replicas can catch up.
+    pub fn settle(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.tick(false);
+        }
+    }
+
+    /// Heals every fault: partitions end, links stop losing, duplicating and
+    /// delaying messages, and crashed replicas restart.
+    pub fn heal(&mut self) {
+        self.record(TraceEvent::Heal);
+        self.heal_partitions();
+        self.network.config.default_link = LinkConfig::default();
+        self.network.config.links.clear();
+        let now = self.now();
+        for replica in &mut self.replicas {
+            if replica.down_until.is_some() {
+                replica.down_until = Some(now);
+            }
+        }
+        self.restart_replicas(now);
+    }
+
+    /// Heals every fault, and checks that within `ticks` ticks every request
+    /// in flight completes and every replica reaches the same commit number.
+    /// If not, returns the state in which the cluster is stuck.
+    pub fn check_liveness(&mut self, ticks: u64) -> Result<(), Stuck> {
+        self.heal();
+        for _ in 0..ticks {
+            if self.is_live() {
+                return Ok(());
+            }
+            self.tick(false);
+        }
+        if self.is_live() {
+            return Ok(());
+        }
+        Err(self.stuck())
+    }
+
+    /// Returns the current simulated time in ticks.
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Returns the number of requests that have completed.
+    pub fn completed(&self) -> usize {
+        self.completed
+    }
+
+    /// Returns the history of the ops the clients have sent.
+    pub fn history(&self) -> &History<SM::Input, SM::Output> {
+        &self.history
+    }
+
+    /// Checks that the history of the ops the clients have sent is
+    /// linearizable with respect to `model`.
+    pub fn check_linearizable<M>(&self, model: &M) -> Result<(), NotLinearizable>
+    where
+        M: Model<Op = SM::Input, Output = SM::Output>,
+    {
+        linearizability::check(model, &self.history)
+    }
+
+    /// Returns the configuration of the simulated network, which can be
+    /// changed between runs to inject faults.
+    pub fn network_mut(&mut self) -> &mut NetworkConfig {
+        &mut self.network.config
+    }
+
+    /// Schedules `partition` to start now and heal after `duration` ticks.
+    pub fn partition(&mut self, partition: Partition, duration: u64) {
+        let start = self.now() + 1;
+        self.partitions.push(ScheduledPartition {
+            partition,
+            start,
+            end: start + duration,
+        });
+    }
+
+    /// Returns the partitions that are active now.
+    pub fn active_partitions(&self) -> Vec<&Partition> {
+        let now = self.now();
+        self.partitions
+            .iter()
+            .filter(|scheduled| scheduled.start <= now && now < scheduled.end)
+            .map(|scheduled| &scheduled.partition)
+            .collect()
+    }
+
+    /// Heals every partition, including the ones scheduled for later.
+    pub fn heal_partitions(&mut self) {
+        self.partitions.clear();
+        self.network.cut_links.clear();
+    }
+
+    /// Crashes replica `replica_id`, which loses all its state and restarts
+    /// after `downtime` ticks. The replica receives no messages while it is
+    /// down.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `replica_id` is the primary, because there are no view
+    /// changes yet.
+    pub fn crash(&mut self, replica_id: ReplicaID, downtime: u64) {
+        self.crash_until(replica_id, self.clock.now() + downtime);
+    }
+
+    fn crash_until(&mut self, replica_id: ReplicaID, until: u64) {
+        assert_ne!(
+            replica_id,
+            self.cluster.primary_id(0),
+            "cannot crash the primary without view changes"
+        );
+        debug!("Crashing replica {} until tick {}", replica_id, until);
+        self.record(TraceEvent::Crash { replica_id, until });
+        let replica = &mut self.replicas[replica_id];
+        replica.down_until = Some(until);
+        replica.outbox.try_iter().for_each(drop);
+    }
+
+    /// Returns true if replica `replica_id` has not crashed, or has
+    /// restarted.
+    pub fn is_up(&self, replica_id: ReplicaID) -> bool {
+        self.replicas[replica_id].down_until.is_none()
+    }
+
+    /// Returns the configuration of the simulated cluster.
+    pub fn cluster(&self) -> &Config {
+        &self.cluster
+    }
+
+    /// Returns replica `replica_id`.
+    pub fn replica(&self, replica_id: ReplicaID) -> &Replica<SM> {
+        &self.replicas[replica_id].replica
+    }
+
+    /// Returns the state machine of replica `replica_id`.
+    pub fn state_machine(&self, replica_id: ReplicaID) -> &SM {
+        &self.replicas[replica_id].state_machine
+    }
+
+    fn is_live(&self) -> bool {
+        let commit_number = self.replicas[0].replica.commit_number();
+        self.clients.iter().all(|client| client.in_flight.is_none())
+            && self.replicas.iter().all(|replica| {
+                replica.down_until.is_none() && replica.replica.commit_number() == commit_number
+            })
+    }
+
+    fn stuck(&self) -> Stuck {
+        let pending_requests = self
+            .clients
+            .iter()
+            .filter_map(|client| Some((client.client_id, client.in_flight?)))
+            .collect();
+        let replicas = self
+            .replicas
+            .iter()
+            .map(|replica| ReplicaState {
+                replica_id: replica.replica.replica_id(),
+                up: replica.down_until.is_none(),
+                status: replica.replica.status(),
+                view_number: replica.replica.view_number(),
+                op_number: replica.replica.op_number(),
+                commit_number: replica.replica.commit_number(),
+            })
+            .collect();
+        Stuck {
+            now: self.now(),
+            pending_requests,
+            replicas,
+        }
+    }
+
+    fn new_replica(&mut self, replica_id: ReplicaID) -> SimReplica<SM> {
+        let state_machine = Arc::new((self.new_state_machine)());
+        let (outbox_tx, outbox) = crossbeam_channel::unbounded();
+        let mut replica = Replica::new(
+            replica_id,
+            self.cluster.clone(),
+            state_machine.clone(),
+            self.reply_tx.clone(),
+            outbox_tx,
+        );
+        let seed = match &mut self.replay {
+            Some(replay) => replay
+                .seeds
+                .get_mut(&replica_id)
+                .and_then(|seeds| seeds.pop_front())
+                .unwrap_or_default(),
+            None => self.rng.gen(),
+        };
+        self.record(TraceEvent::Seed { replica_id, seed });
+        replica.set_clock(self.clock.clone());
+        replica.set_seed(seed);
+        SimReplica {
+            replica,
+            state_machine,
+            outbox,
+            seed,
+            down_until: None,
+        }
+    }
+
+    fn record(&mut self, event: TraceEvent<SM::Input>) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder(&event);
+        }
+    }
+
+    fn tick(&mut self, inject: bool) {
+        // Faults are injected before the clock advances, which is where a
+        // replay applies the crashes it reads from the trace. A replay takes
+        // the faults from the trace.
+        if inject && self.replay.is_none() {
+            self.inject_faults(self.clock.now() + 1);
+        }
+        let now = self.clock.advance();
+        self.restart_replicas(now);
+        self.record(TraceEvent::Tick(now));
+        self.update_partitions(now);
+        if inject {
+            self.send_requests(now);
+        }
+        // `is_multiple_of` needs Rust 1.87.
+        #[allow(clippy::manual_is_multiple_of)]
+        let idle = now % self.config.idle_interval == 0;
+        if idle {
+            for replica_id in 0..self.replicas.len() {
+                if !self.is_up(replica_id) {
+                    continue;
+                }
+                self.replicas[replica_id].replica.on_idle();
+                self.collect_messages(replica_id);
+                self.check_invariants();
+            }
+        }
+        self.deliver_messages(now);
+        self.collect_replies();
+    }
+
+    /// Restarts the crashed replicas whose downtime is over.
+    fn restart_replicas(&mut self, now: u64) {
+        for replica_id in 0..self.replicas.len() {
+            if self.replicas[replica_id]
+                .down_until
+                .is_some_and(|down_until| down_until <= now)
+            {
+                debug!("Restarting replica {}", replica_id);
+                // The replica lost its state, so it starts from scratch and
+                // recovers it from the primary.
+                self.replicas[replica_id] = self.new_replica(replica_id);
+                self.invariants.restart(replica_id);
+                self.replicas[replica_id].replica.recover();
+                self.collect_messages(replica_id);
+                self.check_invariants();
+            }
+        }
+    }
+
+    /// Crashes a random backup with probability
+    /// `SimulatorConfig::crash_probability` if a majority of the replicas
+    /// would still be up, and starts a random partition with probability
+    /// `SimulatorConfig::partition_probability` if none is active.
+    fn inject_faults(&mut self, now: u64) {
+        if self.config.crash_probability > 0.0 {
+            self.crash_random_backup();
+        }
+        if self.config.partition_probability > 0.0
+            && self.active_partitions().is_empty()
+            && self.rng.gen_bool(self.config.partition_probability)
+        {
+            let partition = self.gen_partition();
+            let duration = self.rng.gen_range(1..=self.config.max_partition_duration);
+            self.partitions.push(ScheduledPartition {
+                partition,
+                start: now,
+                end: now + duration,
+            });
+        }
+    }
+
+    fn crash_random_backup(&mut self) {
+        let primary_id = self.cluster.primary_id(0);
+        let up: Vec<ReplicaID> = (0..self.replicas.len())
+            .filter(|replica_id| self.is_up(*replica_id))
+            .collect();
+        if up.len() <= self.cluster.quorum() || !self.rng.gen_bool(self.config.crash_probability) {
+            return;
+        }
+        let backups: Vec<ReplicaID> = up
+            .into_iter()
+            .filter(|replica_id| *replica_id != primary_id)
+            .collect();
+        let replica_id = *backups.choose(&mut self.rng).unwrap();
+        let downtime = self.rng.gen_range(1..=self.config.max_downtime);
+        self.crash(replica_id, downtime);
+    }
+
+    /// Cuts the links of the partitions that are active at time `now`.
+    fn update_partitions(&mut self, now: u64) {
+        if let Some(replay) = &mut self.replay {
+            if let Some(cut_links) = replay.cuts.remove(&now) {
+                self.network.cut_links = cut_links;
+            }
+            return;
+        }
+        self.partitions.retain(|scheduled| scheduled.end > now);
+        let replica_ids = self.cluster.replica_ids();
+        // There are no view changes, so the primary never moves.
+        let primary_id = self.cluster.primary_id(0);
+        let cut_links: HashSet<_> = self
+            .active_partitions()
+            .into_iter()
+            .flat_map(|partition| partition.cut_links(&replica_ids, primary_id))
+            .collect();
+        if cut_links != self.network.cut_links {
+            debug!("Partition cuts links {:?}", cut_links);
+            let mut links: Vec<_> = cut_links.iter().copied().collect();
+            links.sort();
+            self.record(TraceEvent::Cut(links));
+            self.network.cut_links = cut_links;
+        }
+    }
+
+    fn gen_partition(&mut self) -> Partition {
+        let replica_ids = self.cluster.replica_ids();
+        match self.rng.gen_range(0..3) {
+            0 => {
+                let mut group = replica_ids;
+                group.shuffle(&mut self.rng);
+                let minority = self.rng.gen_range(1..=((group.len() - 1) / 2).max(1));
+                group.truncate(minority);
+                Partition::Split(group)
+            }
+            // A link from a replica to itself cuts nothing, so a one-way
+            // partition needs two replicas.
+            2 if replica_ids.len() > 1 => {
+                let from = *replica_ids.choose(&mut self.rng).unwrap();
+                let others: Vec<_> = replica_ids.into_iter().filter(|id| *id != from).collect();
+                let to = *others.choose(&mut self.rng).unwrap();
+                Partition::OneWay { from, to }
+            }
+            _ => Partition::IsolatePrimary,
+        }
+    }
+
+    /// Idle clients send a new request with probability
+    /// `SimulatorConfig::request_probability`.
+    fn send_requests(&mut self, now: u64) {
+        let requests = match &mut self.replay {
+            Some(replay) => replay.requests.remove(&now).unwrap_or_default(),
+            None => {
+                let mut requests = Vec::new();
+                for client in &self.clients {
+                    if client.in_flight.is_some()
+                        || !self.rng.gen_bool(self.config.request_probability)
+                    {
+                        continue;
+                    }
+                    requests.push((client.client_id, self.workload.gen_op(&mut self.rng)));
+                }
+                requests
+            }
+        };
+        for (client_id, op) in requests {
+            self.send_request(now, client_id, op);
+        }
+    }
+
+    fn send_request(&mut self, now: u64, client_id: ClientID, op: SM::Input) {
+        let client = &mut self.clients[client_id];
+        if client.in_flight.is_some() {
+            // A replay of a trace from before a code change can ask a client
+            // for a new request before its previous one completes.
+            warn!(
+                "Client {} skips {:?}, as request {} is in flight",
+                client_id, op, client.request_number
+            );
+            return;
+        }
+        debug!("Client {} sends {:?}", client_id, op);
+        let request_number = client.request_number;
+        client.in_flight = Some(request_number);
+        client.request_number += 1;
+        self.history.invoke(now, client_id, op.clone());
+        self.record(TraceEvent::Request {
+            client_id,
+            op: op.clone(),
+        });
+        // There are no view changes, so the primary never moves.
+        let primary_id = self.cluster.primary_id(0);
+        let message = Message::Request {
+            cluster_id: self.cluster.cluster_id,
+            client_id,
+            request_number,
+            op,
+        };
+        self.send(None, primary_id, message);
+    }
+
+    /// Sends `message` from `from`, or from a client if `from` is `None`, to
+    /// replica `to`.
+    fn send(&mut self, from: Option<ReplicaID>, to: ReplicaID, message: SmMessage<SM>) {
+        let now = self.clock.now();
+        let envelope = Envelope {
+            id: self.network.next_id(from, to),
+            message,
+        };
+        let deliver_at = match &mut self.replay {
+            Some(replay) => match replay.sends.remove(&envelope.id) {
+                Some(deliver_at) => deliver_at,
+                // The message is new since the trace was recorded.
+                None if from.is_some_and(|from| self.network.is_cut(from, to)) => Vec::new(),
+                None => vec![now + self.network.config.min_latency.max(1)],
+            },
+            None => self.network.fate(&mut self.rng, now, &envelope),
+        };
+        self.record(TraceEvent::Send {
+            id: envelope.id,
+            deliver_at: deliver_at.clone(),
+        });
+        self.network.send(envelope, &deliver_at);
+    }
+
+    /// Delivers the messages that are due at time `now`, in order of their
+    /// delivery time.
+    fn deliver_messages(&mut self, now: u64) {
+        while let Some(envelope) = self.network.next_due(now) {
+            let to = envelope.id.to;
+            if !self.is_up(to) {
+                debug!("Dropping {:?} to crashed {}", envelope.message, to);
+                self.record(TraceEvent::Drop(envelope.id));
+                continue;
+            }
+            debug!("Delivering {:?} to {}", envelope.message, to);
+            self.record(TraceEvent::Deliver(envelope.id));
+            self.replicas[to].replica.on_message(envelope.message);
+            self.collect_messages(to);
+            self.check_invariants();
+        }
+    }
+
+    /// Checks the safety invariants across the replicas that are up, and
+    /// panics if they do not hold.
+    fn check_invariants(&mut self) {
+        let replicas = self
+            .replicas
+            .iter()
+            .filter(|replica| replica.down_until.is_none())
+            .map(|replica| &replica.replica);
+        if let Err(violation) = self.invariants.check(replicas) {
+            panic!(
+                "Invariant violated at tick {} with seed {}: {}",
+                self.clock.now(),
+                self.config.seed,
+                violation
+            );
+        }
+    }
+
+    /// Moves the messages replica `replica_id` has sent into the network.
+    fn collect_messages(&mut self, replica_id: ReplicaID) {
+        while let Ok((to, message)) = self.replicas[replica_id].outbox.try_recv() {
+            self.send(Some(replica_id), to, message);
+        }
+    }
+
+    fn collect_replies(&mut self) {
+        while let Ok(reply) = self.reply_rx.try_recv() {
+            let Some(client) = self
+                .clients
+                .iter_mut()
+                .find(|client| client.client_id == reply.client_id)
+            else {
+                continue;
+            };
+            if client.in_flight == Some(reply.request_number) {
+                debug!(
+                    "Client {} receives reply to request {}",
+                    client.client_id, reply.request_number
+                );
+                client.in_flight = None;
+                self.history
+                    .complete(self.clock.now(), client.client_id, reply.result);
+                self.completed += 1;
+            }
+        }
+    }
+}
+
+impl<SM> Simulator<SM>
+where
+    SM: StateMachine + 'static,
+    SM::Input: Display,
+{
+    /// Records the events of the run to a trace file at `path`, from which
+    /// `Trace::load` and `Simulator::from_trace` replay the run. Recording
+    /// must start before the run does.
+    pub fn record_trace(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        assert_eq!(0, self.now());
+        let mut writer = BufWriter::new(File::create(path)?);
+        let mut trace = Trace::<SM::Input>::new(&self.config);
+        trace.events = self
+            .replicas
+            .iter()
+            .map(|replica| TraceEvent::Seed {
+                replica_id: replica.replica.replica_id(),
+                seed: replica.seed,
+            })
+            .collect();
+        write!(writer, "{}", trace)?;
+        self.recorder = Some(Box::new(move |event| {
+            writeln!(writer, "{}", event).expect("failed to write trace");
+        }));
+        Ok(())
+    }
+}
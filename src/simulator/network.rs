@@ -0,0 +1,278 @@
+//! Simulated network.
+//!
+//! Every message gets a delivery time from a seeded latency distribution, so
+//! messages are reordered, and the links between replicas can lose,
+//! duplicate and delay messages. Partitions cut links between replicas for
+//! a while, and heal later. Messages in flight wait in a priority queue
+//! ordered by delivery time.
+
+use crate::message::Message;
+use crate::types::ReplicaID;
+use log::debug;
+use rand::Rng;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt::Debug;
+
+use super::trace::MessageID;
+use super::SimRng;
+
+/// Default minimum latency of a message in ticks.
+pub const DEFAULT_MIN_LATENCY: u64 = 1;
+
+/// Default maximum latency of a message in ticks.
+pub const DEFAULT_MAX_LATENCY: u64 = 5;
+
+/// Fault model of a link from one replica to another.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LinkConfig {
+    /// Probability that a message is lost.
+    pub loss: f64,
+    /// Probability that a message is delivered twice.
+    pub duplication: f64,
+    /// Probability that a message is delayed by up to `max_delay` ticks on
+    /// top of its latency.
+    pub delay: f64,
+    /// Maximum extra delay of a delayed message in ticks.
+    pub max_delay: u64,
+}
+
+/// Network configuration.
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    /// Minimum latency of a message in ticks.
+    pub min_latency: u64,
+    /// Maximum latency of a message in ticks. Latencies are uniformly
+    /// distributed between the minimum and the maximum.
+    pub max_latency: u64,
+    /// Fault model of the links that have no entry in `links`.
+    pub default_link: LinkConfig,
+    /// Fault models of individual links, keyed by sender and receiver.
+    pub links: HashMap<(ReplicaID, ReplicaID), LinkConfig>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> NetworkConfig {
+        NetworkConfig::new()
+    }
+}
+
+impl NetworkConfig {
+    pub fn new() -> NetworkConfig {
+        NetworkConfig {
+            min_latency: DEFAULT_MIN_LATENCY,
+            max_latency: DEFAULT_MAX_LATENCY,
+            default_link: LinkConfig::default(),
+            links: HashMap::default(),
+        }
+    }
+
+    /// Returns the fault model of the link from `from` to `to`.
+    pub fn link(&self, from: ReplicaID, to: ReplicaID) -> &LinkConfig {
+        self.links.get(&(from, to)).unwrap_or(&self.default_link)
+    }
+
+    /// Sets the fault model of the link from `from` to `to`.
+    pub fn set_link(&mut self, from: ReplicaID, to: ReplicaID, link: LinkConfig) {
+        self.links.insert((from, to), link);
+    }
+}
+
+/// A network partition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Partition {
+    /// Splits the replicas into the given group and the rest, which cannot
+    /// reach each other. A group with a minority of the replicas splits the
+    /// cluster into a majority and a minority.
+    Split(Vec<ReplicaID>),
+    /// Isolates the primary from every other replica.
+    IsolatePrimary,
+    /// Cuts the link from `from` to `to`, while `to` can still reach `from`.
+    OneWay { from: ReplicaID, to: ReplicaID },
+}
+
+impl Partition {
+    /// Returns the links the partition cuts, as pairs of sender and receiver.
+    pub fn cut_links(
+        &self,
+        replica_ids: &[ReplicaID],
+        primary_id: ReplicaID,
+    ) -> Vec<(ReplicaID, ReplicaID)> {
+        let split = |group: &[ReplicaID]| {
+            let mut links = Vec::new();
+            for &a in group {
+                for &b in replica_ids.iter().filter(|id| !group.contains(id)) {
+                    links.push((a, b));
+                    links.push((b, a));
+                }
+            }
+            links
+        };
+        match self {
+            Partition::Split(group) => split(group),
+            Partition::IsolatePrimary => split(&[primary_id]),
+            Partition::OneWay { from, to } => vec![(*from, *to)],
+        }
+    }
+}
+
+/// A partition that starts at tick `start` and heals at tick `end`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScheduledPartition {
+    pub partition: Partition,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// A message in flight.
+#[derive(Debug)]
+pub(crate) struct Envelope<Op: Clone + Debug + Send, Query: Clone + Debug + Send> {
+    pub(crate) id: MessageID,
+    pub(crate) message: Message<Op, Query>,
+}
+
+/// A message waiting in the queue for its delivery time. Messages with the
+/// same delivery time are delivered in the order they were sent.
+#[derive(Debug)]
+struct Event<Op: Clone + Debug + Send, Query: Clone + Debug + Send> {
+    deliver_at: u64,
+    seq: u64,
+    envelope: Envelope<Op, Query>,
+}
+
+impl<Op: Clone + Debug + Send, Query: Clone + Debug + Send> PartialEq for Event<Op, Query> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<Op: Clone + Debug + Send, Query: Clone + Debug + Send> Eq for Event<Op, Query> {}
+
+impl<Op: Clone + Debug + Send, Query: Clone + Debug + Send> PartialOrd for Event<Op, Query> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Op: Clone + Debug + Send, Query: Clone + Debug + Send> Ord for Event<Op, Query> {
+    // `BinaryHeap` is a max-heap, so the earliest event compares greatest.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.deliver_at, other.seq).cmp(&(self.deliver_at, self.seq))
+    }
+}
+
+/// Simulated network.
+#[derive(Debug)]
+pub(crate) struct Network<Op: Clone + Debug + Send, Query: Clone + Debug + Send> {
+    pub(crate) config: NetworkConfig,
+    /// Links that partitions currently cut.
+    pub(crate) cut_links: HashSet<(ReplicaID, ReplicaID)>,
+    queue: BinaryHeap<Event<Op, Query>>,
+    seq: u64,
+    /// Number of messages sent over each link.
+    link_seqs: HashMap<(Option<ReplicaID>, ReplicaID), u64>,
+}
+
+impl<Op: Clone + Debug + Send, Query: Clone + Debug + Send> Network<Op, Query> {
+    pub(crate) fn new(config: NetworkConfig) -> Network<Op, Query> {
+        Network {
+            config,
+            cut_links: HashSet::default(),
+            queue: BinaryHeap::new(),
+            seq: 0,
+            link_seqs: HashMap::default(),
+        }
+    }
+
+    /// Returns the ID of the next message from `from` to `to`.
+    pub(crate) fn next_id(&mut self, from: Option<ReplicaID>, to: ReplicaID) -> MessageID {
+        let seq = self.link_seqs.entry((from, to)).or_default();
+        *seq += 1;
+        MessageID {
+            from,
+            to,
+            seq: *seq - 1,
+        }
+    }
+
+    /// Returns the ticks at which the copies of a message sent at time `now`
+    /// are delivered. Messages between replicas are subject to the fault
+    /// model of their link, and are lost if a partition cuts the link.
+    /// Messages already in flight when a partition starts are still
+    /// delivered.
+    pub(crate) fn fate(
+        &self,
+        rng: &mut SimRng,
+        now: u64,
+        envelope: &Envelope<Op, Query>,
+    ) -> Vec<u64> {
+        let link = match envelope.id.from {
+            Some(from) if self.is_cut(from, envelope.id.to) => {
+                debug!(
+                    "Partition cuts {:?} to {}",
+                    envelope.message, envelope.id.to
+                );
+                return Vec::new();
+            }
+            Some(from) => self.config.link(from, envelope.id.to),
+            None => &LinkConfig::default(),
+        };
+        if rng.gen_bool(link.loss) {
+            debug!("Losing {:?} to {}", envelope.message, envelope.id.to);
+            return Vec::new();
+        }
+        let copies = if rng.gen_bool(link.duplication) {
+            debug!("Duplicating {:?} to {}", envelope.message, envelope.id.to);
+            2
+        } else {
+            1
+        };
+        (0..copies)
+            .map(|_| {
+                let mut latency = rng.gen_range(self.config.min_latency..=self.config.max_latency);
+                if link.max_delay > 0 && rng.gen_bool(link.delay) {
+                    latency += rng.gen_range(1..=link.max_delay);
+                }
+                // A message is never delivered in the tick it was sent in.
+                now + latency.max(1)
+            })
+            .collect()
+    }
+
+    /// Returns true if a partition cuts the link from `from` to `to`.
+    pub(crate) fn is_cut(&self, from: ReplicaID, to: ReplicaID) -> bool {
+        self.cut_links.contains(&(from, to))
+    }
+
+    /// Sends a message whose copies are delivered at the ticks `deliver_at`.
+    pub(crate) fn send(&mut self, envelope: Envelope<Op, Query>, deliver_at: &[u64]) {
+        let Some((&last, rest)) = deliver_at.split_last() else {
+            return;
+        };
+        for &deliver_at in rest {
+            let copy = Envelope {
+                id: envelope.id,
+                message: envelope.message.clone(),
+            };
+            self.schedule(deliver_at, copy);
+        }
+        self.schedule(last, envelope);
+    }
+
+    fn schedule(&mut self, deliver_at: u64, envelope: Envelope<Op, Query>) {
+        self.seq += 1;
+        self.queue.push(Event {
+            deliver_at,
+            seq: self.seq,
+            envelope,
+        });
+    }
+
+    /// Removes and returns the next message due at time `now`, if any.
+    pub(crate) fn next_due(&mut self, now: u64) -> Option<Envelope<Op, Query>> {
+        if self.queue.peek()?.deliver_at > now {
+            return None;
+        }
+        self.queue.pop().map(|event| event.envelope)
+    }
+}
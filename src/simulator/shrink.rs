@@ -0,0 +1,125 @@
+//! Shrinking of failing traces.
+//!
+//! A run that fails after many ticks is hard to debug. The shrinker removes
+//! events from its trace for as long as the replay of the trace still fails,
+//! in the style of delta debugging: first in large chunks, then in smaller
+//! ones, until removing any single event makes the failure go away. Removing
+//! a `send` event delivers the message without faults, removing a `tick`
+//! event merges its events into the tick before, and removing a request,
+//! crash, partition or heal leaves it out of the run.
+
+use log::debug;
+use std::fmt::Display;
+
+use super::trace::{Trace, TraceEvent};
+
+/// Shrinks `trace`, for which `fails` returns true, into a trace for which
+/// `fails` still returns true, but no longer does once any one of its
+/// events is removed. Replica seeds are never removed.
+pub fn shrink<Op: Clone>(
+    mut trace: Trace<Op>,
+    mut fails: impl FnMut(&Trace<Op>) -> bool,
+) -> Trace<Op> {
+    assert!(fails(&trace), "trace does not fail");
+    // Deliveries and drops are not replayed, so they do not matter.
+    trace
+        .events
+        .retain(|event| !matches!(event, TraceEvent::Deliver(_) | TraceEvent::Drop(_)));
+    let mut chunk = (removable(&trace).len() / 2).max(1);
+    loop {
+        let mut progress = false;
+        let mut start = 0;
+        loop {
+            let removable = removable(&trace);
+            if start >= removable.len() {
+                break;
+            }
+            let end = (start + chunk).min(removable.len());
+            // Every removable event between the first and the last in the
+            // chunk is in the chunk.
+            let (first, last) = (removable[start], removable[end - 1]);
+            let candidate = Trace {
+                events: trace
+                    .events
+                    .iter()
+                    .enumerate()
+                    .filter(|(idx, event)| {
+                        *idx < first || *idx > last || matches!(event, TraceEvent::Seed { .. })
+                    })
+                    .map(|(_, event)| event.clone())
+                    .collect(),
+                ..trace
+            };
+            if fails(&candidate) {
+                debug!(
+                    "Removed {} events, {} left",
+                    end - start,
+                    candidate.events.len()
+                );
+                trace = candidate;
+                progress = true;
+            } else {
+                start = end;
+            }
+        }
+        if !progress {
+            if chunk == 1 {
+                break;
+            }
+            chunk /= 2;
+        }
+    }
+    // Number the ticks as the replay does.
+    let mut tick = 0;
+    for event in &mut trace.events {
+        if let TraceEvent::Tick(n) = event {
+            tick += 1;
+            *n = tick;
+        }
+    }
+    trace
+}
+
+/// Returns the indexes of the events the shrinker may remove.
+fn removable<Op>(trace: &Trace<Op>) -> Vec<usize> {
+    trace
+        .events
+        .iter()
+        .enumerate()
+        .filter(|(_, event)| !matches!(event, TraceEvent::Seed { .. }))
+        .map(|(idx, _)| idx)
+        .collect()
+}
+
+/// Returns the source code of a test named `name` that replays `trace` with
+/// replicas running state machines created by the expression
+/// `new_state_machine`, and then runs the statements in `check`. The test
+/// expects `Simulator`, `Trace` and the op type of the trace in scope.
+pub fn regression_test<Op: Display>(
+    name: &str,
+    new_state_machine: &str,
+    check: &str,
+    trace: &Trace<Op>,
+) -> String {
+    let trace = trace.to_string();
+    // Use enough hashes in the raw string that the trace cannot end it.
+    let mut hashes = String::from("#");
+    while trace.contains(&format!("\"{}", hashes)) {
+        hashes.push('#');
+    }
+    let mut test = format!(
+        "#[test]\nfn {}() {{\n    let trace = r{}\"\n{}\"{};\n",
+        name, hashes, trace, hashes
+    );
+    test.push_str("    let trace: Trace<Op> = trace.trim_start().parse().unwrap();\n");
+    test.push_str(&format!(
+        "    let mut simulator = Simulator::from_trace(trace, {});\n",
+        new_state_machine
+    ));
+    test.push_str("    simulator.replay();\n");
+    for line in check.lines() {
+        test.push_str(&format!("    {}\n", line.trim()));
+    }
+    test.push_str("}\n");
+    test
+}
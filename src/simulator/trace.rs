@@ -0,0 +1,367 @@
+//! Traces of simulation runs.
+//!
+//! A trace records the schedule of a run: the ticks, the ops clients send,
+//! crashes, partitions and the fate of every message. Replaying a trace
+//! re-drives the same schedule without the random number generator, so the
+//! schedule stays the same even if the replicas behave differently after a
+//! code change.
+//!
+//! The trace file format is line-based, one event per line:
+//!
+//! ```text
+//! cluster 3 3 10
+//! seed 0 8274619283
+//! tick 1
+//! request 2 add 5
+//! send c->0#0 4
+//! tick 4
+//! deliver c->0#0
+//! send 0->1#0 6 9
+//! send 0->2#0
+//! cut 0->1 1->0
+//! crash 2 120
+//! heal
+//! ```
+//!
+//! The header has the number of replicas and clients and the idle interval.
+//! The events of a tick follow its `tick` line. A message is identified by
+//! its sender, where `c` is a client, its receiver, and its sequence number
+//! on the link. A `send` line lists the ticks at which the copies of a
+//! message are delivered: none if the network loses it, and two if it
+//! duplicates it. A `cut` line lists every link that partitions cut from
+//! then on. A `crash` line has the tick at which the replica restarts.
+//! `deliver` and `drop` lines show what happened to messages and are not
+//! replayed. An op is written in its `Display` format and runs to the end
+//! of the line.
+
+use crate::types::{ClientID, ReplicaID};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::SimulatorConfig;
+
+const CLIENT: &str = "c";
+
+/// Identity of a message: the `seq`th message from `from` to `to`.
+/// Messages from clients have no sender replica.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MessageID {
+    pub from: Option<ReplicaID>,
+    pub to: ReplicaID,
+    pub seq: u64,
+}
+
+impl fmt::Display for MessageID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.from {
+            Some(from) => write!(f, "{}->{}#{}", from, self.to, self.seq),
+            None => write!(f, "{}->{}#{}", CLIENT, self.to, self.seq),
+        }
+    }
+}
+
+impl FromStr for MessageID {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<MessageID, ()> {
+        let (from, rest) = s.split_once("->").ok_or(())?;
+        let (to, seq) = rest.split_once('#').ok_or(())?;
+        let from = match from {
+            CLIENT => None,
+            from => Some(from.parse().map_err(|_| ())?),
+        };
+        Ok(MessageID {
+            from,
+            to: to.parse().map_err(|_| ())?,
+            seq: seq.parse().map_err(|_| ())?,
+        })
+    }
+}
+
+/// An event in a trace.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TraceEvent<Op> {
+    /// A replica starts with `seed` as the key of its op seeds.
+    Seed { replica_id: ReplicaID, seed: u64 },
+    /// The clock advances to the given tick.
+    Tick(u64),
+    /// A client sends `op`.
+    Request { client_id: ClientID, op: Op },
+    /// A message is sent, and its copies are delivered at the given ticks.
+    Send { id: MessageID, deliver_at: Vec<u64> },
+    /// A message is delivered.
+    Deliver(MessageID),
+    /// A message is dropped, because its receiver is down.
+    Drop(MessageID),
+    /// Partitions cut the given links, and no others.
+    Cut(Vec<(ReplicaID, ReplicaID)>),
+    /// A replica crashes and restarts at tick `until`.
+    Crash { replica_id: ReplicaID, until: u64 },
+    /// Every fault heals.
+    Heal,
+}
+
+impl<Op: fmt::Display> fmt::Display for TraceEvent<Op> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceEvent::Seed { replica_id, seed } => write!(f, "seed {} {}", replica_id, seed),
+            TraceEvent::Tick(tick) => write!(f, "tick {}", tick),
+            TraceEvent::Request { client_id, op } => write!(f, "request {} {}", client_id, op),
+            TraceEvent::Send { id, deliver_at } => {
+                write!(f, "send {}", id)?;
+                for tick in deliver_at {
+                    write!(f, " {}", tick)?;
+                }
+                Ok(())
+            }
+            TraceEvent::Deliver(id) => write!(f, "deliver {}", id),
+            TraceEvent::Drop(id) => write!(f, "drop {}", id),
+            TraceEvent::Cut(links) => {
+                write!(f, "cut")?;
+                for (from, to) in links {
+                    write!(f, " {}->{}", from, to)?;
+                }
+                Ok(())
+            }
+            TraceEvent::Crash { replica_id, until } => write!(f, "crash {} {}", replica_id, until),
+            TraceEvent::Heal => write!(f, "heal"),
+        }
+    }
+}
+
+/// Trace of a simulation run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trace<Op> {
+    pub replica_count: usize,
+    pub client_count: usize,
+    pub idle_interval: u64,
+    pub events: Vec<TraceEvent<Op>>,
+}
+
+impl<Op> Trace<Op> {
+    /// Returns a trace with no events of a run with `config`.
+    pub fn new(config: &SimulatorConfig) -> Trace<Op> {
+        Trace {
+            replica_count: config.replica_count,
+            client_count: config.client_count,
+            idle_interval: config.idle_interval,
+            events: Vec::new(),
+        }
+    }
+
+    /// Returns the number of ticks in the trace.
+    pub fn ticks(&self) -> u64 {
+        self.events
+            .iter()
+            .filter(|event| matches!(event, TraceEvent::Tick(_)))
+            .count() as u64
+    }
+
+    pub(crate) fn header(&self) -> String {
+        format!(
+            "cluster {} {} {}",
+            self.replica_count, self.client_count, self.idle_interval
+        )
+    }
+}
+
+impl<Op: FromStr> Trace<Op> {
+    /// Loads a trace from a file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Trace<Op>, TraceError> {
+        let contents = std::fs::read_to_string(path)?;
+        contents.parse()
+    }
+}
+
+impl<Op: fmt::Display> Trace<Op> {
+    /// Saves the trace to a file in the format understood by `load`.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), TraceError> {
+        std::fs::write(path, self.to_string())?;
+        Ok(())
+    }
+}
+
+impl<Op: FromStr> FromStr for Trace<Op> {
+    type Err = TraceError;
+
+    fn from_str(s: &str) -> Result<Trace<Op>, TraceError> {
+        let mut lines = s.lines().enumerate();
+        let parse_error = |line: usize, message: &str| TraceError::Parse {
+            line: line + 1,
+            message: message.to_string(),
+        };
+        let header = lines.next().map_or("", |(_, line)| line);
+        let header: Vec<&str> = header.split_whitespace().collect();
+        let (replica_count, client_count, idle_interval) = match header[..] {
+            ["cluster", replica_count, client_count, idle_interval] => (
+                replica_count.parse(),
+                client_count.parse(),
+                idle_interval.parse(),
+            ),
+            _ => return Err(parse_error(0, "expected `cluster` header")),
+        };
+        let (Ok(replica_count), Ok(client_count), Ok(idle_interval)) =
+            (replica_count, client_count, idle_interval)
+        else {
+            return Err(parse_error(0, "invalid `cluster` header"));
+        };
+        let mut events = Vec::new();
+        for (idx, line) in lines {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (kind, args) = line.split_once(' ').unwrap_or((line, ""));
+            let event = parse_event(kind, args).ok_or_else(|| parse_error(idx, "invalid event"))?;
+            events.push(event);
+        }
+        Ok(Trace {
+            replica_count,
+            client_count,
+            idle_interval,
+            events,
+        })
+    }
+}
+
+fn parse_event<Op: FromStr>(kind: &str, args: &str) -> Option<TraceEvent<Op>> {
+    let mut fields = args.split_whitespace();
+    let event = match kind {
+        "seed" => TraceEvent::Seed {
+            replica_id: fields.next()?.parse().ok()?,
+            seed: fields.next()?.parse().ok()?,
+        },
+        "tick" => TraceEvent::Tick(fields.next()?.parse().ok()?),
+        "request" => {
+            let (client_id, op) = args.split_once(' ')?;
+            return Some(TraceEvent::Request {
+                client_id: client_id.parse().ok()?,
+                op: op.parse().ok()?,
+            });
+        }
+        "send" => TraceEvent::Send {
+            id: fields.next()?.parse().ok()?,
+            deliver_at: fields
+                .by_ref()
+                .map(|tick| tick.parse().ok())
+                .collect::<Option<_>>()?,
+        },
+        "deliver" => TraceEvent::Deliver(fields.next()?.parse().ok()?),
+        "drop" => TraceEvent::Drop(fields.next()?.parse().ok()?),
+        "cut" => TraceEvent::Cut(
+            fields
+                .by_ref()
+                .map(|link| {
+                    let (from, to) = link.split_once("->")?;
+                    Some((from.parse().ok()?, to.parse().ok()?))
+                })
+                .collect::<Option<_>>()?,
+        ),
+        "crash" => TraceEvent::Crash {
+            replica_id: fields.next()?.parse().ok()?,
+            until: fields.next()?.parse().ok()?,
+        },
+        "heal" => TraceEvent::Heal,
+        _ => return None,
+    };
+    if fields.next().is_some() {
+        return None;
+    }
+    Some(event)
+}
+
+impl<Op: fmt::Display> fmt::Display for Trace<Op> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.header())?;
+        for event in &self.events {
+            writeln!(f, "{}", event)?;
+        }
+        Ok(())
+    }
+}
+
+/// A trace being replayed, indexed by what the simulator looks up.
+#[derive(Debug)]
+pub(crate) struct Replay<Op> {
+    /// The ticks, crashes and heals, in order.
+    pub(crate) schedule: VecDeque<TraceEvent<Op>>,
+    /// The seeds of each replica, in the order the replica starts.
+    pub(crate) seeds: HashMap<ReplicaID, VecDeque<u64>>,
+    /// The ops clients send, keyed by tick.
+    pub(crate) requests: HashMap<u64, Vec<(ClientID, Op)>>,
+    /// The links partitions cut, keyed by the tick in which they change.
+    pub(crate) cuts: HashMap<u64, HashSet<(ReplicaID, ReplicaID)>>,
+    /// The delivery ticks of each message.
+    pub(crate) sends: HashMap<MessageID, Vec<u64>>,
+}
+
+impl<Op> Replay<Op> {
+    /// Indexes the events of `trace`. The events after the nth `tick` line
+    /// happen in tick n, whatever tick the line names, so removing a `tick`
+    /// line merges its events into the tick before.
+    pub(crate) fn new(trace: Trace<Op>) -> Replay<Op> {
+        let mut replay = Replay {
+            schedule: VecDeque::new(),
+            seeds: HashMap::new(),
+            requests: HashMap::new(),
+            cuts: HashMap::new(),
+            sends: HashMap::new(),
+        };
+        let mut tick = 0;
+        for event in trace.events {
+            match event {
+                TraceEvent::Seed { replica_id, seed } => {
+                    replay.seeds.entry(replica_id).or_default().push_back(seed);
+                }
+                TraceEvent::Tick(_) => {
+                    tick += 1;
+                    replay.schedule.push_back(event);
+                }
+                TraceEvent::Request { client_id, op } => {
+                    replay
+                        .requests
+                        .entry(tick)
+                        .or_default()
+                        .push((client_id, op));
+                }
+                TraceEvent::Send { id, deliver_at } => {
+                    replay.sends.insert(id, deliver_at);
+                }
+                TraceEvent::Deliver(_) | TraceEvent::Drop(_) => {}
+                TraceEvent::Cut(links) => {
+                    replay.cuts.insert(tick, links.into_iter().collect());
+                }
+                TraceEvent::Crash { .. } | TraceEvent::Heal => replay.schedule.push_back(event),
+            }
+        }
+        replay
+    }
+}
+
+/// Trace error.
+#[derive(Debug)]
+pub enum TraceError {
+    /// Reading or writing the trace file failed.
+    Io(std::io::Error),
+    /// The trace file is malformed.
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::Io(err) => write!(f, "I/O error: {}", err),
+            TraceError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl From<std::io::Error> for TraceError {
+    fn from(err: std::io::Error) -> TraceError {
+        TraceError::Io(err)
+    }
+}
@@ -1,9 +1,67 @@
+use crate::message::OpContext;
 use std::fmt::Debug;
 
+/// An error from applying an op to the state machine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ApplyError {
+    /// The op fails in the same way on every replica, for example because it
+    /// is invalid in the current state. The op still counts as applied and
+    /// the client receives `ReplyError::Failed`.
+    Deterministic(String),
+    /// The op failed for a reason local to this replica, such as an I/O
+    /// error. The replica stops applying ops and reports itself unhealthy.
+    Fatal(String),
+}
+
 /// State machine.
 pub trait StateMachine {
     type Input: Clone + Debug + Send;
     type Output;
+    /// A read-only query, which is distinct from `Input` so that reads need
+    /// not be encoded as ops and ops cannot be sent as queries.
+    type Query: Clone + Debug + Send;
 
     fn apply(&self, input: Self::Input) -> Self::Output;
+
+    /// Applies `input` with the context the primary assigned to it. State
+    /// machines that need the current time or randomness must take them from
+    /// `context` rather than from the local replica, or replicas diverge.
+    fn apply_with_context(&self, input: Self::Input, context: &OpContext) -> Self::Output {
+        let _ = context;
+        self.apply(input)
+    }
+
+    /// Applies `input` with the context the primary assigned to it, and
+    /// returns an error if applying fails. A panic is treated like an
+    /// `ApplyError::Fatal` error.
+    fn try_apply(
+        &self,
+        input: Self::Input,
+        context: &OpContext,
+    ) -> Result<Self::Output, ApplyError> {
+        Ok(self.apply_with_context(input, context))
+    }
+
+    /// Validates `input` before the primary appends it to the log. An op
+    /// that is rejected is never replicated, and the client receives
+    /// `ReplyError::Rejected` with the returned reason.
+    fn validate(&self, input: &Self::Input) -> Result<(), String> {
+        let _ = input;
+        Ok(())
+    }
+
+    /// Executes a read-only query. Queries are not appended to the log, so
+    /// `query` must not change the state of the state machine. A query that is
+    /// rejected, as every query is by default, fails with
+    /// `ReplyError::Rejected` with the returned reason.
+    fn query(&self, query: Self::Query) -> Result<Self::Output, String> {
+        let _ = query;
+        Err("queries are not supported".to_string())
+    }
+
+    /// Returns the size of `input` in bytes, which the primary uses to limit
+    /// the size of request batches.
+    fn input_size(&self, input: &Self::Input) -> usize {
+        std::mem::size_of_val(input)
+    }
 }
@@ -1,4 +1,5 @@
 pub type ClientID = usize;
+pub type ClusterID = u64;
 pub type CommitID = usize;
 pub type OpNumber = usize;
 pub type ReplicaID = usize;
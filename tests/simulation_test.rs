@@ -1,3 +1,6 @@
+// The simulation runs all replicas on one thread.
+#![allow(clippy::arc_with_non_send_sync)]
+
 use log::debug;
 use parking_lot::Mutex;
 use rand::prelude::*;
@@ -157,6 +160,7 @@ impl Accumulator {
 impl StateMachine for Accumulator {
     type Input = Op;
     type Output = ();
+    type Query = ();
 
     fn apply(&self, op: Op) {
         let mut accumulator = self.accumulator.lock();
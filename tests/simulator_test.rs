@@ -0,0 +1,603 @@
+#![cfg(feature = "simulator")]
+
+use parking_lot::Mutex;
+use rand::prelude::*;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use vsr_rs::simulator::linearizability;
+use vsr_rs::simulator::{
+    regression_test, shrink, History, InvariantViolation, Invariants, LinkConfig, Model,
+    NotLinearizable, Partition, ScheduledPartition, SimRng, Simulator, SimulatorConfig, Trace,
+    TraceEvent,
+};
+use vsr_rs::{Config, LogEntry, Message, OpContext, Replica, ReplyError, StateMachine, Status};
+
+#[test]
+fn test_simulator() {
+    let seed = seed();
+    let _ = env_logger::try_init();
+    let mut simulator = Simulator::new(SimulatorConfig::new(seed), Accumulator::new, gen_op);
+    if let Ok(path) = std::env::var("TRACE") {
+        simulator.record_trace(path).unwrap();
+    }
+    simulator.run(10000);
+    simulator.settle(100);
+    assert!(simulator.completed() > 0);
+    let accumulator = *simulator.state_machine(0).accumulator.lock();
+    let commit_number = simulator.replica(0).commit_number();
+    for replica_id in simulator.cluster().replica_ids() {
+        let replica = simulator.replica(replica_id);
+        assert_eq!(Status::Normal, replica.status());
+        assert_eq!(commit_number, replica.commit_number());
+        assert_eq!(
+            accumulator,
+            *simulator.state_machine(replica_id).accumulator.lock()
+        );
+    }
+}
+
+#[test]
+fn test_network_faults() {
+    let seed = seed();
+    let _ = env_logger::try_init();
+    let mut config = SimulatorConfig::new(seed);
+    config.network.max_latency = 10;
+    config.network.default_link = LinkConfig {
+        loss: 0.05,
+        duplication: 0.05,
+        delay: 0.05,
+        max_delay: 50,
+    };
+    // The link from the primary to one backup is much worse than the others.
+    config.network.set_link(
+        0,
+        2,
+        LinkConfig {
+            loss: 0.3,
+            ..config.network.default_link.clone()
+        },
+    );
+    let mut simulator = Simulator::new(config, Accumulator::new, gen_op);
+    simulator.run(10000);
+    let completed = simulator.completed();
+    assert!(completed > 0);
+    // Once the network recovers, every replica catches up.
+    simulator.network_mut().default_link = LinkConfig::default();
+    simulator.network_mut().links.clear();
+    simulator.settle(500);
+    let accumulator = *simulator.state_machine(0).accumulator.lock();
+    for replica_id in simulator.cluster().replica_ids() {
+        assert_eq!(
+            accumulator,
+            *simulator.state_machine(replica_id).accumulator.lock()
+        );
+    }
+}
+
+#[test]
+fn test_partitions() {
+    let seed = seed();
+    let _ = env_logger::try_init();
+    let mut config = SimulatorConfig::new(seed);
+    config.replica_count = 5;
+    config.partitions = vec![
+        ScheduledPartition {
+            partition: Partition::Split(vec![3, 4]),
+            start: 1000,
+            end: 2000,
+        },
+        ScheduledPartition {
+            partition: Partition::OneWay { from: 1, to: 0 },
+            start: 3000,
+            end: 4000,
+        },
+    ];
+    let mut simulator = Simulator::new(config, Accumulator::new, gen_op);
+    simulator.run(1100);
+    assert_eq!(
+        vec![&Partition::Split(vec![3, 4])],
+        simulator.active_partitions()
+    );
+    // The majority side of the split makes progress.
+    let completed = simulator.completed();
+    simulator.run(800);
+    assert!(simulator.completed() > completed);
+    simulator.run(2100);
+    // An isolated primary makes no progress.
+    simulator.partition(Partition::IsolatePrimary, 1000);
+    simulator.run(100);
+    let completed = simulator.completed();
+    simulator.run(800);
+    assert_eq!(completed, simulator.completed());
+    // After the partition heals, the cluster makes progress again.
+    simulator.run(1000);
+    assert!(simulator.active_partitions().is_empty());
+    assert!(simulator.completed() > completed);
+    simulator.settle(500);
+    let accumulator = *simulator.state_machine(0).accumulator.lock();
+    for replica_id in simulator.cluster().replica_ids() {
+        assert_eq!(
+            accumulator,
+            *simulator.state_machine(replica_id).accumulator.lock()
+        );
+    }
+}
+
+#[test]
+fn test_random_partitions() {
+    let seed = seed();
+    let _ = env_logger::try_init();
+    let mut config = SimulatorConfig::new(seed);
+    config.partition_probability = 0.01;
+    let mut simulator = Simulator::new(config, Accumulator::new, gen_op);
+    for _ in 0..10000 {
+        simulator.run(1);
+        // Every random partition cuts a link.
+        for partition in simulator.active_partitions() {
+            if let Partition::OneWay { from, to } = partition {
+                assert_ne!(from, to);
+            }
+        }
+    }
+    simulator.heal_partitions();
+    simulator.settle(500);
+    let accumulator = *simulator.state_machine(0).accumulator.lock();
+    for replica_id in simulator.cluster().replica_ids() {
+        assert_eq!(
+            accumulator,
+            *simulator.state_machine(replica_id).accumulator.lock()
+        );
+    }
+}
+
+#[test]
+fn test_crash_restart() {
+    let seed = seed();
+    let _ = env_logger::try_init();
+    let mut config = SimulatorConfig::new(seed);
+    config.replica_count = 5;
+    config.crash_probability = 0.005;
+    let mut simulator = Simulator::new(config, Accumulator::new, gen_op);
+    simulator.crash(1, 100);
+    simulator.run(1);
+    assert!(!simulator.is_up(1));
+    // The cluster makes progress while a majority of the replicas is up.
+    for _ in 0..10 {
+        let completed = simulator.completed();
+        simulator.run(1000);
+        assert!(simulator.completed() > completed);
+    }
+    simulator.settle(1000);
+    let accumulator = *simulator.state_machine(0).accumulator.lock();
+    for replica_id in simulator.cluster().replica_ids() {
+        assert!(simulator.is_up(replica_id));
+        assert_eq!(
+            accumulator,
+            *simulator.state_machine(replica_id).accumulator.lock()
+        );
+    }
+}
+
+#[test]
+#[should_panic(expected = "cannot crash the primary")]
+fn test_crash_primary() {
+    let mut simulator = Simulator::new(SimulatorConfig::new(0), Accumulator::new, gen_op);
+    let primary_id = simulator.cluster().primary_id(0);
+    simulator.crash(primary_id, 10);
+}
+
+#[test]
+fn test_simulator_determinism() {
+    let run = |seed| {
+        let mut simulator = Simulator::new(SimulatorConfig::new(seed), Accumulator::new, gen_op);
+        simulator.run(1000);
+        let accumulator = *simulator.state_machine(0).accumulator.lock();
+        (simulator.completed(), accumulator)
+    };
+    let seed = seed();
+    assert_eq!(run(seed), run(seed));
+}
+
+#[test]
+fn test_replay() {
+    let _ = env_logger::try_init();
+    if let Ok(path) = std::env::var("REPLAY") {
+        let trace = Trace::load(path).unwrap();
+        let mut simulator = Simulator::from_trace(trace, Accumulator::new);
+        simulator.replay();
+        return;
+    }
+    let seed = seed();
+    let mut config = SimulatorConfig::new(seed);
+    config.replica_count = 5;
+    config.network.default_link = LinkConfig {
+        loss: 0.05,
+        duplication: 0.05,
+        delay: 0.05,
+        max_delay: 50,
+    };
+    config.partition_probability = 0.005;
+    config.crash_probability = 0.005;
+    let path = trace_path("test_replay", seed);
+    let mut simulator = Simulator::new(config, Accumulator::new, gen_op);
+    simulator.record_trace(&path).unwrap();
+    simulator.run(2000);
+    simulator.crash(1, 100);
+    simulator.run(1000);
+    simulator.check_liveness(1000).unwrap();
+    let now = simulator.now();
+    let history = simulator.history().operations().to_vec();
+    let accumulator = *simulator.state_machine(0).accumulator.lock();
+    // Dropping the simulator flushes the trace.
+    drop(simulator);
+    let trace: Trace<Op> = Trace::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(now, trace.ticks());
+    assert_eq!(trace, trace.to_string().parse().unwrap());
+    let mut replayed = Simulator::from_trace(trace, Accumulator::new);
+    replayed.replay();
+    assert_eq!(now, replayed.now());
+    assert_eq!(history, replayed.history().operations());
+    for replica_id in replayed.cluster().replica_ids() {
+        assert_eq!(
+            accumulator,
+            *replayed.state_machine(replica_id).accumulator.lock()
+        );
+    }
+}
+
+#[test]
+fn test_shrink() {
+    let _ = env_logger::try_init();
+    if let Ok(path) = std::env::var("SHRINK") {
+        // Shrink a trace whose replay panics, and keep the panics quiet
+        // while doing so.
+        let trace: Trace<Op> = Trace::load(&path).unwrap();
+        let hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(|_| {}));
+        let trace = shrink(trace, |trace| {
+            std::panic::catch_unwind(|| {
+                Simulator::from_trace(trace.clone(), Accumulator::new).replay();
+            })
+            .is_err()
+        });
+        std::panic::set_hook(hook);
+        trace.save(format!("{}.min", path)).unwrap();
+        println!(
+            "{}",
+            regression_test("test_regression", "Accumulator::new", "", &trace)
+        );
+        return;
+    }
+    let seed = seed();
+    let mut config = SimulatorConfig::new(seed);
+    config.network.default_link = LinkConfig {
+        loss: 0.05,
+        duplication: 0.05,
+        delay: 0.05,
+        max_delay: 50,
+    };
+    config.crash_probability = 0.005;
+    let path = trace_path("test_shrink", seed);
+    let mut simulator = Simulator::new(config, Accumulator::new, gen_op);
+    simulator.record_trace(&path).unwrap();
+    simulator.run(500);
+    drop(simulator);
+    let trace: Trace<Op> = Trace::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    // The "bug" is that a client completes a subtraction.
+    let fails = |trace: &Trace<Op>| {
+        let mut simulator = Simulator::from_trace(trace.clone(), Accumulator::new);
+        simulator.replay();
+        simulator.history().operations().iter().any(|operation| {
+            matches!(
+                (&operation.op, &operation.ret),
+                (Op::Sub(_), Some((_, Ok(_))))
+            )
+        })
+    };
+    let shrunk = shrink(trace, fails);
+    assert!(fails(&shrunk));
+    let requests = shrunk
+        .events
+        .iter()
+        .filter(|event| matches!(event, TraceEvent::Request { .. }))
+        .count();
+    assert_eq!(1, requests);
+    // Removing any event makes the failure go away.
+    for idx in 0..shrunk.events.len() {
+        if matches!(shrunk.events[idx], TraceEvent::Seed { .. }) {
+            continue;
+        }
+        let mut smaller = shrunk.clone();
+        smaller.events.remove(idx);
+        assert!(!fails(&smaller));
+    }
+    let test = regression_test(
+        "test_regression",
+        "Accumulator::new",
+        "assert_eq!(0, simulator.completed());",
+        &shrunk,
+    );
+    assert!(test.starts_with("#[test]\nfn test_regression() {\n"));
+    assert!(test.contains(&shrunk.to_string()));
+    assert!(test.contains("    assert_eq!(0, simulator.completed());\n}\n"));
+}
+
+#[test]
+fn test_liveness() {
+    let seed = seed();
+    let _ = env_logger::try_init();
+    let mut config = SimulatorConfig::new(seed);
+    config.replica_count = 5;
+    config.network.default_link = LinkConfig {
+        loss: 0.1,
+        duplication: 0.05,
+        delay: 0.05,
+        max_delay: 50,
+    };
+    config.partition_probability = 0.01;
+    config.crash_probability = 0.005;
+    let mut simulator = Simulator::new(config, Accumulator::new, gen_op);
+    simulator.run(5000);
+    if let Err(stuck) = simulator.check_liveness(1000) {
+        panic!("{}", stuck);
+    }
+    for replica_id in simulator.cluster().replica_ids() {
+        assert!(simulator.is_up(replica_id));
+    }
+}
+
+#[test]
+fn test_liveness_stuck() {
+    let mut config = SimulatorConfig::new(0);
+    config.request_probability = 1.0;
+    let mut simulator = Simulator::new(config, Accumulator::new, gen_op);
+    simulator.run(1);
+    // No request can complete without any ticks.
+    let stuck = simulator.check_liveness(0).unwrap_err();
+    assert_eq!(vec![(0, 0), (1, 0), (2, 0)], stuck.pending_requests);
+    assert_eq!(3, stuck.replicas.len());
+    assert!(stuck
+        .replicas
+        .iter()
+        .all(|replica| replica.up && replica.status == Status::Normal));
+    simulator.check_liveness(100).unwrap();
+}
+
+#[test]
+fn test_invariants() {
+    #[allow(clippy::arc_with_non_send_sync)]
+    let config = Arc::new(Config::new());
+    for _ in 0..3 {
+        config.add_replica();
+    }
+    let (client_tx, _client_rx) = crossbeam_channel::unbounded();
+    let (replica_tx, _replica_rx) = crossbeam_channel::unbounded();
+    let backup = |replica_id| {
+        let state_machine = Arc::new(Accumulator::new());
+        Replica::new(
+            replica_id,
+            config.clone(),
+            state_machine,
+            client_tx.clone(),
+            replica_tx.clone(),
+        )
+    };
+    let prepare = |client_id| Message::Prepare {
+        cluster_id: config.cluster_id,
+        view_number: 0,
+        op_number: 1,
+        entries: vec![LogEntry {
+            client_id,
+            request_number: 0,
+            op: Op::Add(1),
+            context: OpContext::default(),
+        }],
+        commit_number: 1,
+    };
+    // Two backups commit different ops at the same position.
+    let b1 = backup(1);
+    b1.on_message(prepare(0));
+    let b2 = backup(2);
+    b2.on_message(prepare(1));
+    let mut invariants = Invariants::new();
+    assert_eq!(
+        Err(InvariantViolation::CommittedLogMismatch {
+            replica_id: 2,
+            op_number: 1
+        }),
+        invariants.check([&b1, &b2])
+    );
+    // A replica forgets what it committed, unless it restarted.
+    let mut invariants = Invariants::new();
+    invariants.check([&b1]).unwrap();
+    let restarted = backup(1);
+    assert_eq!(
+        Err(InvariantViolation::CommitNumberDecreased {
+            replica_id: 1,
+            from: 1,
+            to: 0
+        }),
+        invariants.check([&restarted])
+    );
+    invariants.restart(1);
+    invariants.check([&restarted]).unwrap();
+}
+
+#[test]
+fn test_linearizability() {
+    let seed = seed();
+    let _ = env_logger::try_init();
+    let mut config = SimulatorConfig::new(seed);
+    config.replica_count = 5;
+    config.client_count = 5;
+    config.network.default_link = LinkConfig {
+        loss: 0.05,
+        duplication: 0.05,
+        delay: 0.05,
+        max_delay: 50,
+    };
+    config.partition_probability = 0.005;
+    config.crash_probability = 0.005;
+    let mut simulator = Simulator::new(config, Accumulator::new, gen_op);
+    simulator.run(5000);
+    assert!(simulator.completed() > 0);
+    simulator.check_linearizable(&AccumulatorModel).unwrap();
+}
+
+#[test]
+fn test_linearizability_checker() {
+    // Client 1 reads a value that client 0 has not written yet.
+    let mut history = History::new();
+    history.invoke(0, 1, Op::Add(0));
+    history.complete(1, 1, Ok(1));
+    history.invoke(2, 0, Op::Add(1));
+    history.complete(3, 0, Ok(1));
+    assert_eq!(
+        Err(NotLinearizable {
+            linearized: 0,
+            operations: 2
+        }),
+        linearizability::check(&AccumulatorModel, &history)
+    );
+    // The same ops are linearizable if they are concurrent.
+    let mut history = History::new();
+    history.invoke(0, 0, Op::Add(1));
+    history.invoke(1, 1, Op::Add(0));
+    history.complete(2, 1, Ok(1));
+    history.complete(3, 0, Ok(1));
+    assert!(linearizability::check(&AccumulatorModel, &history).is_ok());
+    // A pending op may have taken effect, and a busy one has not.
+    let mut history = History::new();
+    history.invoke(0, 0, Op::Add(1));
+    history.invoke(1, 1, Op::Sub(5));
+    history.complete(2, 1, Err(ReplyError::Busy));
+    history.invoke(3, 2, Op::Add(0));
+    history.complete(4, 2, Ok(1));
+    assert!(linearizability::check(&AccumulatorModel, &history).is_ok());
+    // Neither has an op that an unhealthy replica refused.
+    let mut history = History::new();
+    history.invoke(0, 0, Op::Add(1));
+    history.complete(1, 0, Err(ReplyError::Unhealthy("crash".to_string())));
+    history.invoke(2, 1, Op::Add(0));
+    history.complete(3, 1, Ok(0));
+    assert!(linearizability::check(&AccumulatorModel, &history).is_ok());
+}
+
+/// Sequential model of `Accumulator`.
+struct AccumulatorModel;
+
+impl Model for AccumulatorModel {
+    type State = i32;
+    type Op = Op;
+    type Output = i32;
+
+    fn init(&self) -> i32 {
+        0
+    }
+
+    fn step(&self, state: &i32, op: &Op) -> (i32, Result<i32, ReplyError>) {
+        let state = match op {
+            Op::Add(value) => state.wrapping_add(*value),
+            Op::Sub(value) => state.wrapping_sub(*value),
+        };
+        (state, Ok(state))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Op {
+    Add(i32),
+    Sub(i32),
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Add(value) => write!(f, "add {}", value),
+            Op::Sub(value) => write!(f, "sub {}", value),
+        }
+    }
+}
+
+impl FromStr for Op {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Op, String> {
+        let (op, value) = s.split_once(' ').ok_or("missing operand")?;
+        let value = value.parse().map_err(|_| "invalid operand")?;
+        match op {
+            "add" => Ok(Op::Add(value)),
+            "sub" => Ok(Op::Sub(value)),
+            _ => Err(format!("unknown op {}", op)),
+        }
+    }
+}
+
+/// Returns the seed in the `SEED` environment variable, or a random one, and
+/// prints it so that a failing run can be reproduced.
+fn seed() -> u64 {
+    let seed = match std::env::var("SEED") {
+        Ok(seed) => seed.parse::<u64>().unwrap(),
+        Err(_) => rand::thread_rng().next_u64(),
+    };
+    println!("Seed: {}", seed);
+    seed
+}
+
+/// Returns a temporary path for the trace of test `test`. Tests that record
+/// traces run in parallel, possibly with the same seed, so every test and
+/// test process gets its own file.
+fn trace_path(test: &str, seed: u64) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "vsr-rs-trace-{}-{}-{}",
+        test,
+        seed,
+        std::process::id()
+    ))
+}
+
+fn gen_op(rng: &mut SimRng) -> Op {
+    let value = rng.next_u32() as i32;
+    match rng.gen_range(0..2) {
+        0 => Op::Add(value),
+        _ => Op::Sub(value),
+    }
+}
+
+struct Accumulator {
+    accumulator: Mutex<i32>,
+}
+
+impl Accumulator {
+    fn new() -> Accumulator {
+        let accumulator = Mutex::new(0);
+        Accumulator { accumulator }
+    }
+}
+
+impl StateMachine for Accumulator {
+    type Input = Op;
+    type Output = i32;
+    type Query = ();
+
+    fn apply(&self, op: Op) -> i32 {
+        let mut accumulator = self.accumulator.lock();
+        match op {
+            Op::Add(value) => {
+                *accumulator = accumulator.wrapping_add(value);
+            }
+            Op::Sub(value) => {
+                *accumulator = accumulator.wrapping_sub(value);
+            }
+        }
+        *accumulator
+    }
+
+    fn query(&self, _query: ()) -> Result<i32, String> {
+        Ok(*self.accumulator.lock())
+    }
+}